@@ -0,0 +1,235 @@
+extern crate docopt;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate regex;
+#[cfg(test)]
+extern crate tempfile;
+#[macro_use]
+extern crate rust_miscs;
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process::{self, exit};
+
+use regex::Regex;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_from: String,
+  flag_to: String,
+  flag_dry_run: bool,
+  flag_no_clobber: bool,
+  arg_dir: Option<String>,
+}
+
+const USAGE: &str = r"
+Rename files by a regular expression
+
+Usage:
+  brename [options] --from=<REGEX> --to=<REPLACEMENT> [<dir>]
+  brename (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --from=<REGEX>    The pattern matched against the file names
+  --to=<REPLACEMENT>
+                    The new file name, which can refer to the captures of the
+                    pattern as $1, $2, ... or ${name}
+  -n --dry-run      Print the renames as 'old -> new' instead of running them
+  --no-clobber      Skip the renames whose targets already exist
+
+The files are read from stdin (one per line) unless <dir> is given,
+in which case the files directly under it are renamed.
+";
+
+def_error! {
+  RenameError {
+    Io(io::Error)       => "I/O error: {0}",
+    Regex(regex::Error) => "invalid pattern: {0}",
+    Other(String)       => "{0}",
+  }
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  if let Err(e) = run(args) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
+
+fn run(args: Args) -> Result<(), RenameError> {
+  let from = Regex::new(&args.flag_from)?;
+
+  let paths: Vec<PathBuf> = match args.arg_dir {
+    Some(ref dir) => {
+      let mut paths = Vec::new();
+      for entry in fs::read_dir(dir)? {
+        paths.push(entry?.path());
+      }
+      paths.sort();
+      paths
+    }
+    None => {
+      let stdin = io::stdin();
+      let mut paths = Vec::new();
+      for line in stdin.lock().lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+          paths.push(PathBuf::from(line));
+        }
+      }
+      paths
+    }
+  };
+
+  let renames = plan_renames(&paths, &from, &args.flag_to, args.flag_no_clobber)?;
+
+  if args.flag_dry_run {
+    for (old, new) in &renames {
+      println!("{} -> {}", old.display(), new.display());
+    }
+    return Ok(());
+  }
+
+  // A target may be another source, e.g. when swapping two names (a -> b, b -> a).
+  // In that case all files are moved to temporary names first, so that none is overwritten.
+  let sources: HashSet<&PathBuf> = renames.iter().map(|(old, _)| old).collect();
+  if renames.iter().any(|(_, new)| sources.contains(new)) {
+    rename_via_temps(&renames)?;
+  } else {
+    for (old, new) in &renames {
+      fs::rename(old, new)?;
+    }
+  }
+
+  Ok(())
+}
+
+// Rename the files through temporary names.
+//
+// When a rename fails, the files already moved are renamed back to their old paths.
+fn rename_via_temps(renames: &[(PathBuf, PathBuf)]) -> Result<(), RenameError> {
+  let mut temps = Vec::with_capacity(renames.len());
+  for (i, (old, _)) in renames.iter().enumerate() {
+    let temp = old.with_file_name(format!(".brename-{}-{}.tmp", process::id(), i));
+    if let Err(e) = fs::rename(old, &temp) {
+      roll_back(renames, &temps, 0);
+      return Err(format!("{}: {}", old.display(), e).into());
+    }
+    temps.push(temp);
+  }
+  for (i, (temp, (_, new))) in temps.iter().zip(renames).enumerate() {
+    if let Err(e) = fs::rename(temp, new) {
+      roll_back(renames, &temps, i);
+      return Err(format!("{}: {}", new.display(), e).into());
+    }
+  }
+  Ok(())
+}
+
+// Move the files back to their old paths, after the first `moved` files have been renamed
+// from `temps` to their targets.
+//
+// The files which cannot be restored are reported with their current paths.
+fn roll_back(renames: &[(PathBuf, PathBuf)], temps: &[PathBuf], moved: usize) {
+  let mut current: Vec<&PathBuf> = temps.iter().collect();
+  for i in (0..moved).rev() {
+    let (_, ref new) = renames[i];
+    match fs::rename(new, &temps[i]) {
+      Ok(()) => (),
+      Err(e) => {
+        eprintln!("{}: {}", new.display(), e);
+        current[i] = new;
+      }
+    }
+  }
+  for (i, (old, _)) in renames.iter().enumerate().take(temps.len()) {
+    if current[i] == &temps[i] {
+      match fs::rename(&temps[i], old) {
+        Ok(()) => current[i] = old,
+        Err(e) => eprintln!("{}: {}", temps[i].display(), e),
+      }
+    }
+    if current[i] != old {
+      eprintln!("not restored: {} (was {})", current[i].display(), old.display());
+    }
+  }
+}
+
+// Compute the pairs of the old and new paths. The replacement is applied to the file names.
+fn plan_renames(paths: &[PathBuf],
+                from: &Regex,
+                to: &str,
+                no_clobber: bool)
+                -> Result<Vec<(PathBuf, PathBuf)>, RenameError> {
+  let mut renames = Vec::new();
+  let mut targets = HashSet::new();
+  for path in paths {
+    let name = match path.file_name() {
+      Some(name) => name.to_string_lossy().into_owned(),
+      None => continue,
+    };
+    if !from.is_match(&name) {
+      continue;
+    }
+    let new_name = from.replace(&name, to).into_owned();
+    if new_name == name {
+      continue;
+    }
+    if new_name.is_empty() || new_name.contains('/') {
+      return Err(format!("{}: invalid new name '{}'", path.display(), new_name).into());
+    }
+
+    let new_path = path.with_file_name(&new_name);
+    if !targets.insert(new_path.clone()) {
+      return Err(format!("{}: more than one file would be renamed to it", new_path.display())
+        .into());
+    }
+    renames.push((path.clone(), new_path));
+  }
+
+  if no_clobber {
+    // The existing file is not overwritten, unless it is also renamed to another name.
+    let sources: HashSet<PathBuf> = renames.iter().map(|(old, _)| old.clone()).collect();
+    renames.retain(|(old, new)| {
+      let clobbers = new.exists() && !sources.contains(new);
+      if clobbers {
+        eprintln!("skipped: {} -> {} (already exists)", old.display(), new.display());
+      }
+      !clobbers
+    });
+  }
+
+  Ok(renames)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  #[cfg(unix)]
+  fn failed_renames_are_rolled_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, b, c) = (dir.path().join("a"), dir.path().join("b"), dir.path().join("c"));
+    fs::write(&a, "a").unwrap();
+    fs::write(&b, "b").unwrap();
+    // A file cannot replace a non-empty directory, so the second rename fails.
+    fs::create_dir(&c).unwrap();
+    fs::write(c.join("x"), "x").unwrap();
+
+    let renames = vec![(a.clone(), b.clone()), (b.clone(), c.clone())];
+    assert!(rename_via_temps(&renames).is_err());
+
+    assert_eq!(fs::read_to_string(&a).unwrap(), "a");
+    assert_eq!(fs::read_to_string(&b).unwrap(), "b");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+  }
+}
@@ -0,0 +1,90 @@
+//! Building and running commands from the argument templates, as `jsonargs` does.
+
+use std::io;
+use std::process::{Child, Command, Stdio};
+
+use handlebars::{Handlebars, RenderError, TemplateError};
+use rustc_serialize::json::ToJson;
+
+// The name which each template is registered as.
+const TEMPLATE_NAME: &str = "dummy";
+
+/// Compile each argument into a template.
+#[allow(clippy::result_large_err)]
+pub fn compile_templates<I, S>(args: I) -> Result<Vec<Handlebars>, TemplateError>
+  where I: IntoIterator<Item = S>,
+        S: AsRef<str>
+{
+  args.into_iter()
+    .map(|arg| -> Result<Handlebars, TemplateError> {
+      let mut handlebars = Handlebars::new();
+      handlebars.register_template_string(TEMPLATE_NAME, arg.as_ref())?;
+      Ok(handlebars)
+    })
+    .collect()
+}
+
+/// Render the templates with the fields of a record, into the arguments of a command.
+///
+/// The templates must be the ones returned from `compile_templates`.
+pub fn expand_templates<T: ToJson>(templates: &[Handlebars],
+                                   json_object: &T)
+                                   -> Result<Vec<String>, RenderError> {
+  templates.iter().map(|template| template.render(TEMPLATE_NAME, json_object)).collect()
+}
+
+/// Build a command with the arguments.
+///
+/// The standard input is inherited from the current process, unless `parallel` is set.
+/// In that case the commands would compete for it, so they are given an empty input.
+pub fn build_command(name: &str, args: &[String], parallel: bool) -> Command {
+  let mut command = Command::new(name);
+  command.args(args)
+    .stdin(if parallel { Stdio::null() } else { Stdio::inherit() })
+    .stdout(Stdio::inherit())
+    .stderr(Stdio::inherit());
+  command
+}
+
+/// Spawn a command with the arguments. See `build_command` for the configuration.
+pub fn run_command(name: &str, args: &[String], parallel: bool) -> io::Result<Child> {
+  build_command(name, args, parallel).spawn()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::BTreeMap;
+
+  fn record(fields: &[(&str, &str)]) -> BTreeMap<String, String> {
+    fields.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect()
+  }
+
+  #[test]
+  fn missing_key_is_rendered_as_empty() {
+    let templates = compile_templates(["a={{a}}", "b={{b}}"]).unwrap();
+    let args = expand_templates(&templates, &record(&[("a", "1")])).unwrap();
+    assert_eq!(args, vec!["a=1", "b="]);
+  }
+
+  #[test]
+  fn undefined_helper_is_a_render_error() {
+    let templates = compile_templates(["{{foo a}}"]).unwrap();
+    assert!(expand_templates(&templates, &record(&[("a", "1")])).is_err());
+  }
+
+  #[test]
+  fn empty_template() {
+    let templates = compile_templates([""]).unwrap();
+    assert_eq!(expand_templates(&templates, &record(&[])).unwrap(), vec![""]);
+  }
+
+  #[test]
+  fn special_characters_are_kept() {
+    // The arguments are passed to the command as is, without the shell.
+    let value = "$HOME; rm -rf * | 'quoted'\n\\ \u{3042}";
+    let templates = compile_templates(["{{a}}", "{{{b}}}"]).unwrap();
+    let args = expand_templates(&templates, &record(&[("a", value), ("b", "<&\">")])).unwrap();
+    assert_eq!(args, vec![value, "<&\">"]);
+  }
+}
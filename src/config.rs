@@ -0,0 +1,5 @@
+//! Default values shared among the utilities.
+
+/// The default capacity of the channel which passes the found entries from the scanning
+/// threads to the output.
+pub const DEFAULT_CHANNEL_BUFFER: usize = 256;
@@ -0,0 +1,145 @@
+//! A parser of `.env` files.
+//!
+//! Each line is in the form of `KEY=value`. Empty lines and the lines starting with `#` are
+//! skipped, and an `=` in the key can be escaped as `\=`. The value may be quoted: in double
+//! quotes `\"`, `\\` and `\n` are unescaped, and in single quotes it is taken literally.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Read the variables from the `.env` file.
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<BTreeMap<String, String>> {
+  let mut content = String::new();
+  File::open(path)?.read_to_string(&mut content)?;
+  parse(&content)
+}
+
+/// Parse the content of a `.env` file.
+///
+/// If a key appears more than once, the last one wins.
+pub fn parse(content: &str) -> io::Result<BTreeMap<String, String>> {
+  let mut vars = BTreeMap::new();
+  for (i, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let (key, value) = parse_line(line).map_err(|message| {
+      io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", i + 1, message))
+    })?;
+    vars.insert(key, value);
+  }
+  Ok(vars)
+}
+
+fn parse_line(line: &str) -> Result<(String, String), String> {
+  let mut key = String::new();
+  let mut chars = line.char_indices().peekable();
+  let mut value = None;
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '\\' if chars.peek().map(|&(_, c)| c) == Some('=') => {
+        chars.next();
+        key.push('=');
+      }
+      '=' => {
+        value = Some(&line[i + 1..]);
+        break;
+      }
+      c => key.push(c),
+    }
+  }
+
+  let value = value.ok_or_else(|| format!("expected KEY=value: {}", line))?;
+  let key = key.trim();
+  if key.is_empty() {
+    return Err(format!("the key is empty: {}", line));
+  }
+  Ok((key.to_owned(), parse_value(value.trim())?))
+}
+
+fn parse_value(value: &str) -> Result<String, String> {
+  if let Some(quoted) = value.strip_prefix('\'') {
+    return match quoted.strip_suffix('\'') {
+      Some(inner) => Ok(inner.to_owned()),
+      None => Err(format!("unterminated quote: {}", value)),
+    };
+  }
+
+  if let Some(quoted) = value.strip_prefix('"') {
+    let mut unescaped = String::new();
+    let mut chars = quoted.chars();
+    loop {
+      match chars.next() {
+        Some('"') => break,
+        Some('\\') => {
+          match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => return Err(format!("unterminated quote: {}", value)),
+          }
+        }
+        Some(c) => unescaped.push(c),
+        None => return Err(format!("unterminated quote: {}", value)),
+      }
+    }
+    if !chars.as_str().trim().is_empty() {
+      return Err(format!("unexpected characters after the quote: {}", value));
+    }
+    return Ok(unescaped);
+  }
+
+  Ok(value.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn var<'a>(vars: &'a BTreeMap<String, String>, key: &str) -> &'a str {
+    &vars[key]
+  }
+
+  #[test]
+  fn parse_skips_comments_and_empty_lines() {
+    let vars = parse("# comment\n\nA=1\n  B = 2  \n").unwrap();
+    assert_eq!(vars.len(), 2);
+    assert_eq!(var(&vars, "A"), "1");
+    assert_eq!(var(&vars, "B"), "2");
+  }
+
+  #[test]
+  fn parse_quoted_values() {
+    let vars = parse(r#"A="a \"b\" \\ c\nd"
+B='a \n "b"'
+C="x=y""#)
+      .unwrap();
+    assert_eq!(var(&vars, "A"), "a \"b\" \\ c\nd");
+    assert_eq!(var(&vars, "B"), r#"a \n "b""#);
+    assert_eq!(var(&vars, "C"), "x=y");
+  }
+
+  #[test]
+  fn parse_escaped_equal_sign_in_key() {
+    let vars = parse(r"A\=B=C=D").unwrap();
+    assert_eq!(var(&vars, "A=B"), "C=D");
+  }
+
+  #[test]
+  fn parse_last_one_wins() {
+    let vars = parse("A=1\nA=2\n").unwrap();
+    assert_eq!(var(&vars, "A"), "2");
+  }
+
+  #[test]
+  fn parse_malformed_lines() {
+    for content in &["A", "=1", "A=\"1", "A='1", "A=\"1\" 2"] {
+      let err = parse(&format!("B=0\n{}\n", content)).unwrap_err();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+      assert!(err.to_string().starts_with("line 2: "), "{}", err);
+    }
+  }
+}
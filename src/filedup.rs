@@ -0,0 +1,256 @@
+extern crate docopt;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate sha2;
+#[cfg(test)]
+extern crate tempfile;
+#[macro_use]
+extern crate rust_miscs;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use rust_miscs::traversal::{self, WalkOptions};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Keep {
+  First,
+  Last,
+  None,
+}
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_min_size: u64,
+  flag_delete_keep: Keep,
+  arg_roots: Vec<String>,
+}
+
+const USAGE: &str = r"
+Find the files with the same content
+
+Usage:
+  filedup [options] [<roots>...]
+  filedup (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --min-size=<BYTES>
+                    Skip the files smaller than BYTES [default: 1]
+  --delete-keep=<WHICH>
+                    Delete the duplicates but the first or last one (in the order of
+                    the paths) of each group: first, last or none [default: none]
+
+The groups of the duplicated files are printed, separated by blank lines.
+With --delete-keep, each deleted file is printed as 'deleted: PATH' after its
+group. The groups whose files are all hard links to the same file are kept.
+The files which cannot be read are skipped with a warning.
+";
+
+def_error! {
+  FiledupError {
+    Io(io::Error) => "I/O error: {0}",
+  }
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  let stdout = io::stdout();
+  if let Err(e) = run(stdout.lock(), args) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
+
+fn run<W: Write>(mut out: W, args: Args) -> Result<(), FiledupError> {
+  let roots = if args.arg_roots.is_empty() {
+    vec![".".to_owned()]
+  } else {
+    args.arg_roots
+  };
+
+  // Only the files of the same size can be duplicates, so the others are not hashed at all.
+  let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+  for root in &roots {
+    for entry in traversal::walk(root.as_str(), &WalkOptions::default()) {
+      let entry = match entry {
+        Ok(entry) => entry,
+        Err(e) => {
+          eprintln!("warning: {}", e);
+          continue;
+        }
+      };
+      let metadata = match entry.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => {
+          eprintln!("warning: {}: {}", entry.path().display(), e);
+          continue;
+        }
+      };
+      if !metadata.is_file() {
+        continue;
+      }
+      let size = metadata.len();
+      if size < args.flag_min_size {
+        continue;
+      }
+      by_size.entry(size).or_default().push(entry.path().to_owned());
+    }
+  }
+
+  // The groups are keyed by the hash, so that the output is stable.
+  let mut groups: BTreeMap<[u8; 32], Vec<PathBuf>> = BTreeMap::new();
+  for (_, paths) in by_size.into_iter().filter(|(_, paths)| paths.len() > 1) {
+    for path in paths {
+      let hash = match content_hash(&path) {
+        Ok(hash) => hash,
+        Err(e) => {
+          eprintln!("warning: {}: {}", path.display(), e);
+          continue;
+        }
+      };
+      groups.entry(hash).or_default().push(path);
+    }
+  }
+
+  let mut is_first = true;
+  for (_, mut paths) in groups.into_iter().filter(|(_, paths)| paths.len() > 1) {
+    paths.sort();
+    // The same file is found twice when the roots overlap.
+    paths.dedup();
+    if paths.len() < 2 {
+      continue;
+    }
+    if !is_first {
+      writeln!(out)?;
+    }
+    for path in &paths {
+      writeln!(out, "{}", path.display())?;
+    }
+    is_first = false;
+
+    let deleted = match args.flag_delete_keep {
+      Keep::First => &paths[1..],
+      Keep::Last => &paths[..paths.len() - 1],
+      Keep::None => &[],
+    };
+    if !deleted.is_empty() && is_same_file(&paths)? {
+      eprintln!("warning: not deleted, since the files are hard links to the same file: {}",
+                paths[0].display());
+      continue;
+    }
+    for path in deleted {
+      fs::remove_file(path)?;
+      writeln!(out, "deleted: {}", path.display())?;
+    }
+  }
+
+  out.flush()?;
+  Ok(())
+}
+
+// Return whether all of the paths refer to the same file, i.e. they are hard links.
+#[cfg(unix)]
+fn is_same_file(paths: &[PathBuf]) -> io::Result<bool> {
+  use std::os::unix::fs::MetadataExt;
+  let mut ids = Vec::with_capacity(paths.len());
+  for path in paths {
+    let metadata = fs::metadata(path)?;
+    ids.push((metadata.dev(), metadata.ino()));
+  }
+  Ok(ids.windows(2).all(|w| w[0] == w[1]))
+}
+
+// Hard links are not detected on non-Unix platforms.
+#[cfg(not(unix))]
+fn is_same_file(_paths: &[PathBuf]) -> io::Result<bool> {
+  Ok(false)
+}
+
+// Compute the SHA-256 of the content of the file.
+fn content_hash(path: &Path) -> io::Result<[u8; 32]> {
+  let mut file = File::open(path)?;
+  let mut hasher = Sha256::new();
+  let mut buf = [0u8; 64 * 1024];
+  loop {
+    let len = file.read(&mut buf)?;
+    if len == 0 {
+      break;
+    }
+    hasher.update(&buf[..len]);
+  }
+
+  let mut hash = [0u8; 32];
+  hash.copy_from_slice(&hasher.finalize());
+  Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn filedup(root: &Path, keep: Keep) -> String {
+    let args = Args {
+      flag_min_size: 1,
+      flag_delete_keep: keep,
+      arg_roots: vec![root.to_str().unwrap().to_owned()],
+    };
+    let mut out = Vec::new();
+    run(&mut out, args).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn deleted_files_are_printed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::write(&a, "x").unwrap();
+    fs::write(&b, "x").unwrap();
+
+    let output = filedup(dir.path(), Keep::First);
+    assert_eq!(output, format!("{0}\n{1}\ndeleted: {1}\n", a.display(), b.display()));
+    assert!(a.exists());
+    assert!(!b.exists());
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn hard_links_are_not_deleted() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::write(&a, "x").unwrap();
+    fs::hard_link(&a, &b).unwrap();
+
+    let output = filedup(dir.path(), Keep::Last);
+    assert!(!output.contains("deleted:"), "{}", output);
+    assert!(a.exists());
+    assert!(b.exists());
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn unreadable_files_are_skipped() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::write(&a, "x").unwrap();
+    fs::write(&b, "x").unwrap();
+    fs::set_permissions(&b, fs::Permissions::from_mode(0o000)).unwrap();
+    // The permissions do not apply to root.
+    if File::open(&b).is_ok() {
+      return;
+    }
+
+    assert_eq!(filedup(dir.path(), Keep::None), "");
+  }
+}
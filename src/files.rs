@@ -1,44 +1,128 @@
 extern crate clap;
 extern crate regex;
+extern crate glob;
+extern crate rustc_serialize;
+extern crate chrono;
+extern crate sha2;
+extern crate crossbeam;
+extern crate rand;
+#[cfg(test)]
+extern crate tempfile;
+#[macro_use]
+extern crate rust_miscs;
 
-use std::{env, fs, io, thread};
+use std::{env, fs, io};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
 use std::borrow::{Borrow, Cow};
-use std::ops::Deref;
-use std::path::{Path, PathBuf, StripPrefixError};
-use std::sync::{Arc, mpsc};
+use std::ops::Range;
+use std::path::{Path, PathBuf, StripPrefixError, MAIN_SEPARATOR};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[macro_export]
-macro_rules! def_from {
-  ($t:ident, $src:ty => $dst:ident) => {
-    impl From<$src> for $t {
-      fn from(err: $src) -> $t {
-        $t::$dst(err)
-      }
-    }
+use rust_miscs::{Contexted, ExtContext};
+use rust_miscs::command;
+use rust_miscs::config::DEFAULT_CHANNEL_BUFFER;
+use rust_miscs::index;
+use rust_miscs::pattern::{GlobPattern, Pattern};
+use rust_miscs::traversal::{self, skip_permission_error, WalkOptions, WalkResult};
+use rustc_serialize::json::Json;
+use sha2::{Digest, Sha256};
+use crossbeam::channel::Receiver;
+use rand::Rng;
+
+def_error! {
+  FilesError {
+    Regex(regex::Error)           => "invalid pattern: {0}",
+    IO(io::Error)                 => "I/O error: {0}",
+    StripPrefix(StripPrefixError) => "{0}",
+    Glob(glob::PatternError)      => "invalid glob pattern: {0}",
+    Other(String)                 => "{0}",
+    Path(Contexted<io::Error>)    => "{0}",
   }
 }
 
-#[derive(Debug)]
-enum FilesError {
-  Regex(regex::Error),
-  IO(io::Error),
-  StripPrefix(StripPrefixError),
-  Other(String),
+
+// The directories of the version control systems, which are ignored by default.
+const VCS_PATTERN: &str = r"^(\.git|\.hg|\.svn|_darcs|\.bzr)$";
+
+#[derive(Debug, Clone, Copy)]
+enum SortKey {
+  Name,
+  Size,
+  Mtime,
+  // The score of `--fuzzy`, in descending order.
+  Score,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColorMode {
+  Auto,
+  Always,
+  Never,
 }
-def_from! { FilesError, regex::Error     => Regex }
-def_from! { FilesError, io::Error        => IO }
-def_from! { FilesError, StripPrefixError => StripPrefix }
-def_from! { FilesError, String           => Other }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+  Text,
+  Json,
+  Nul,
+  Tree,
+}
 
 #[derive(Debug)]
 struct Cli {
-  matchre: Option<regex::Regex>,
-  ignore: Arc<Option<regex::Regex>>,
+  matchre: Pattern,
+  fuzzy: Option<String>,
+  glob: GlobPattern,
+  content_match: Option<regex::Regex>,
+  output_metadata: bool,
+  limit_per_dir: Option<usize>,
+  skip_empty_dirs: bool,
+  cross_fs: bool,
+  ignore: Pattern,
   is_async: bool,
+  parallel_rayon: bool,
   is_directory: bool,
   is_absolute: bool,
   max_items: usize,
+  max_depth: usize,
+  sort: Option<SortKey>,
+  follow_symlinks: bool,
+  gitignore: bool,
+  output_format: OutputFormat,
+  min_size: Option<u64>,
+  max_size: Option<u64>,
+  newer_than: Option<SystemTime>,
+  older_than: Option<SystemTime>,
+  roots: Vec<PathBuf>,
+  extensions: Vec<String>,
+  count: bool,
+  count_limit: bool,
+  prune: bool,
+  channel_buffer: usize,
+  skip_permission_errors: bool,
+  output_file: Option<PathBuf>,
+  deduplicate: bool,
+  verbose: bool,
+  index_output: Option<PathBuf>,
+  index_input: Option<PathBuf>,
+  stats: bool,
+  color: bool,
+  progress: bool,
+  relative_to: Option<PathBuf>,
+  sample: Option<usize>,
+  exec: Option<Vec<String>>,
+  exec_parallel: bool,
+}
+
+// A directory where the scan starts.
+#[derive(Debug)]
+struct Root {
+  // The path given by the user, which the displayed paths are relative to.
+  path: PathBuf,
+  canonical: PathBuf,
 }
 
 impl Cli {
@@ -58,134 +142,1196 @@ impl Cli {
       .version("0.0.1")
       .author("Yusuke Sasaki <yusuke.sasaki.nuem@gmail.com>")
       .setting(AppSettings::VersionlessSubcommands)
-      .arg(Arg::from_usage("-i --ignore=[IGNORE]   'Ignored pattern'"))
+      .arg(Arg::from_usage("-i --ignore=[IGNORE]... 'Ignored pattern (can be repeated)'")
+        .number_of_values(1))
       .arg(Arg::from_usage("-m --matches=[MATCHES] 'Pattern to match'"))
+      .arg(Arg::from_usage("--glob=[PATTERN]       'Glob pattern to match, e.g. *.rs'"))
+      .arg(Arg::from_usage("-C --content-match=[REGEX] 'Show only files whose content matches'"))
+      .arg(Arg::from_usage("--fuzzy=[QUERY]        'Fuzzy query to match, sorting by the score'")
+        .conflicts_with("matches"))
+      .arg(Arg::from_usage("--ignore-case          'Match the patterns case-insensitively'"))
       .arg(Arg::from_usage("-a --absolute          'Show absolute path'"))
+      .arg(Arg::from_usage("--relative-to=[PATH]   'Show paths relative to PATH'")
+        .conflicts_with("absolute"))
       .arg(Arg::from_usage("-d --directory         'Show only directories'"))
+      .arg(Arg::from_usage("--skip-empty-dirs      'Omit directories without any file under them'")
+        .requires("directory"))
       .arg(Arg::from_usage("-A --async             'Search asynchronously'"))
+      .arg(Arg::from_usage("--parallel-rayon       'Search in parallel on the Rayon thread pool'"))
       .arg(Arg::from_usage("-M --max-items=[N]     'Limit of displayed items'"))
+      .arg(Arg::from_usage("--sample=[N]           'Show N entries chosen at random'"))
+      .arg(Arg::from_usage("-D --max-depth=[N]     'Maximum depth of traversal'"))
+      .arg(Arg::from_usage("--limit-per-dir=[N]    'Limit of displayed items in each directory'"))
+      .arg(Arg::from_usage("--sort=[KEY]           'Sort entries by the given key'")
+        .possible_values(&["name", "size", "mtime", "none"]))
+      .arg(Arg::from_usage("-L --follow-symlinks   'Follow symbolic links'"))
+      .arg(Arg::from_usage("--no-cross-fs          'Do not descend into other filesystems'"))
+      .arg(Arg::from_usage("-g --gitignore         'Skip entries ignored by .gitignore files'"))
+      .arg(Arg::from_usage("--output-format=[FMT]  'Output format'")
+        .possible_values(&["text", "json", "nul", "tree"]))
+      .arg(Arg::from_usage("--min-size=[SIZE]      'Minimum size of files, e.g. 10K'"))
+      .arg(Arg::from_usage("--max-size=[SIZE]      'Maximum size of files, e.g. 10M'"))
+      .arg(Arg::from_usage("--newer-than=[DATE]    'Modified after DATE (RFC 3339)'"))
+      .arg(Arg::from_usage("--older-than=[DATE]    'Modified before DATE (RFC 3339)'"))
+      .arg(Arg::from_usage("-e --extension=[EXT]... 'Show only files with the extension'")
+        .number_of_values(1))
+      .arg(Arg::from_usage("-c --count             'Show only the number of entries'"))
+      .arg(Arg::from_usage("--count-limit          'Apply --max-items to the count'")
+        .requires("count"))
+      .arg(Arg::from_usage("--prune                'Omit ignored directories entirely'"))
+      .arg(Arg::from_usage("-H --hidden            'Include hidden files and directories'")
+        .alias("include-hidden"))
+      .arg(Arg::from_usage("--no-vcs-ignore        'Do not ignore the VCS directories by default'"))
+      .arg(Arg::from_usage("--no-include-hidden    'Exclude hidden files (default)'")
+        .overrides_with("hidden"))
+      .arg(Arg::from_usage("--channel-buffer=[N]   'Capacity of the entry channel'"))
+      .arg(Arg::from_usage("--skip-permission-errors 'Warn on permission errors (default)'"))
+      .arg(Arg::from_usage("--no-skip-permission-errors 'Abort on permission errors'")
+        .overrides_with("skip-permission-errors"))
+      .arg(Arg::from_usage("-0 --print0")
+        .help("Separate the paths by NUL, e.g. files --print0 | xargs -0 cmd")
+        .conflicts_with("output-format"))
+      .arg(Arg::from_usage("--color=[WHEN]         'Highlight the matched part of the paths'")
+        .possible_values(&["auto", "always", "never"]))
+      .arg(Arg::from_usage("--output-metadata      'Append the size and mtime, separated by tabs'"))
+      .arg(Arg::from_usage("-o --output-file=[PATH] 'Write the results to PATH'"))
+      .arg(Arg::from_usage("--deduplicate          'Omit files with duplicated content'")
+        .alias("deduplicate-by-content"))
+      .arg(Arg::from_usage("--progress             'Report the number of found entries to stderr'"))
+      .arg(Arg::from_usage("--stats                'Report the numbers of entries to stderr'"))
+      .arg(Arg::from_usage("-v --verbose           'Report the skipped entries to stderr'"))
+      .arg(Arg::from_usage("--index-output=[PATH]  'Write the paths into the index file at PATH'"))
+      .arg(Arg::from_usage("--index-input=[PATH]   'Query the index at PATH instead of scanning'")
+        .conflicts_with_all(&["ROOTS", "index-output"]))
+      .arg(Arg::from_usage("--exec=[CMD]...        'Run CMD ... ; for each entry ({} is the path)'")
+        .allow_hyphen_values(true)
+        .value_terminator(";"))
+      .arg(Arg::from_usage("--parallel             'Run the commands of --exec at once'")
+        .requires("exec"))
+      .arg(Arg::from_usage("[ROOTS]...             'Directories to scan (default: .)'"))
   }
 
   pub fn new() -> Result<Cli, FilesError> {
-    let matches = Self::build_app().get_matches();
+    Self::from_matches(&Self::build_app().get_matches())
+  }
+
+  fn from_matches(matches: &clap::ArgMatches) -> Result<Cli, FilesError> {
+    let ignore_case = matches.is_present("ignore-case");
 
     let matchre = match matches.value_of("matches") {
-      Some(s) => Some(regex::Regex::new(s)?),
+      Some(s) => Pattern::new(&case_pattern(s, ignore_case))?,
+      None => Pattern::empty(),
+    };
+    let glob = match matches.value_of("glob") {
+      Some(s) => GlobPattern::new(s)?,
+      None => GlobPattern::empty(),
+    };
+    let content_match = match matches.value_of("content-match") {
+      Some(s) => Some(regex::Regex::new(&case_pattern(s, ignore_case))?),
       None => None,
     };
 
-    let ignore: Cow<str> = matches.value_of("ignore")
-      .map(Into::into)
+    // `--hidden` shows the dot-files, but the VCS directories are still ignored by default.
+    let include_hidden = matches.is_present("hidden");
+    let vcs_ignore = !matches.is_present("no-vcs-ignore");
+    // The repeated patterns are combined into one, which matches if any of them matches.
+    // An empty pattern (`--ignore ''`) matches nothing, as before.
+    let ignore: Cow<str> = matches.values_of("ignore")
+      .map(|patterns| {
+        let patterns: Vec<_> =
+          patterns.filter(|p| !p.is_empty()).map(|p| format!("(?:{})", p)).collect();
+        patterns.join("|").into()
+      })
       .or(env::var("FILES_IGNORE_PATTERN").ok().map(Into::into))
-      .unwrap_or(r#"^(\.git|\.hg|\.svn|_darcs|\.bzr)$"#.into());
-    let ignore = if (ignore.borrow() as &str) != "" {
-      Some(regex::Regex::new(ignore.borrow())?)
-    } else {
-      None
+      .unwrap_or(if vcs_ignore {
+        VCS_PATTERN.into()
+      } else {
+        "".into()
+      });
+    let ignore: Cow<str> = match (include_hidden, ignore.borrow() as &str) {
+      (true, _) => ignore.clone(),
+      (false, "") => r"^\.".into(),
+      (false, pattern) => format!(r"^\.|(?:{})", pattern).into(),
     };
-    let ignore = Arc::new(ignore);
+    let mut ignore = Pattern::new(&case_pattern(ignore.borrow(), ignore_case))?;
+    // The VCS directories are controlled only by `--no-vcs-ignore`, not by the dot-file rule.
+    if !vcs_ignore && !include_hidden {
+      ignore = ignore.except(VCS_PATTERN)?;
+    }
 
     let max_items =
-      matches.value_of("max-items").and_then(|s| s.parse().ok()).unwrap_or(usize::max_value());
+      matches.value_of("max-items").and_then(|s| s.parse().ok()).unwrap_or(usize::MAX);
+    let max_depth =
+      matches.value_of("max-depth").and_then(|s| s.parse().ok()).unwrap_or(usize::MAX);
+
+    let sample = match matches.value_of("sample") {
+      Some(s) => Some(s.parse().map_err(|_| format!("invalid sample size: {}", s))?),
+      None => None,
+    };
+
+    let limit_per_dir = match matches.value_of("limit-per-dir") {
+      Some(s) => Some(s.parse().map_err(|_| format!("invalid limit per directory: {}", s))?),
+      None => None,
+    };
+
+    let channel_buffer = match matches.value_of("channel-buffer") {
+      Some(s) => {
+        match s.parse() {
+          Ok(n) if n >= 1 => n,
+          _ => return Err(format!("invalid channel buffer size: {}", s).into()),
+        }
+      }
+      None => DEFAULT_CHANNEL_BUFFER,
+    };
+
+    let sort = match matches.value_of("sort") {
+      Some("name") => Some(SortKey::Name),
+      Some("size") => Some(SortKey::Size),
+      Some("mtime") => Some(SortKey::Mtime),
+      Some("none") => None,
+      _ if matches.is_present("fuzzy") => Some(SortKey::Score),
+      _ => None,
+    };
+
+    // The found entries are canonical paths, so the base must be canonicalized as well.
+    let relative_to = match matches.value_of("relative-to") {
+      Some(path) => Some(fs::canonicalize(path).with_context(|| path.to_owned())?),
+      None => None,
+    };
+
+    let output_format = match matches.value_of("output-format") {
+      _ if matches.is_present("print0") => OutputFormat::Nul,
+      Some("json") => OutputFormat::Json,
+      Some("nul") => OutputFormat::Nul,
+      Some("tree") => OutputFormat::Tree,
+      _ => OutputFormat::Text,
+    };
+
+    let color_mode = match matches.value_of("color") {
+      Some("always") => ColorMode::Always,
+      Some("never") => ColorMode::Never,
+      _ => ColorMode::Auto,
+    };
+    let color = match color_mode {
+      ColorMode::Always => true,
+      ColorMode::Never => false,
+      ColorMode::Auto => {
+        !matches.is_present("output-file") && io::stdout().is_terminal() &&
+        env::var("TERM").map(|term| term != "dumb").unwrap_or(false)
+      }
+    };
+
+    let min_size = match matches.value_of("min-size") {
+      Some(s) => Some(parse_size(s)?),
+      None => None,
+    };
+    let max_size = match matches.value_of("max-size") {
+      Some(s) => Some(parse_size(s)?),
+      None => None,
+    };
+
+    let newer_than = match matches.value_of("newer-than") {
+      Some(s) => Some(parse_datetime(s)?),
+      None => None,
+    };
+    let older_than = match matches.value_of("older-than") {
+      Some(s) => Some(parse_datetime(s)?),
+      None => None,
+    };
 
     Ok(Cli {
-      matchre: matchre,
-      ignore: ignore,
+      matchre,
+      fuzzy: matches.value_of("fuzzy").map(ToOwned::to_owned),
+      glob,
+      content_match,
+      output_metadata: matches.is_present("output-metadata"),
+      limit_per_dir,
+      skip_empty_dirs: matches.is_present("skip-empty-dirs"),
+      cross_fs: !matches.is_present("no-cross-fs"),
+      ignore,
       is_directory: matches.is_present("directory"),
       is_absolute: matches.is_present("absolute"),
       is_async: matches.is_present("async"),
-      max_items: max_items,
+      parallel_rayon: matches.is_present("parallel-rayon"),
+      max_items,
+      max_depth,
+      sort,
+      follow_symlinks: matches.is_present("follow-symlinks"),
+      gitignore: matches.is_present("gitignore"),
+      output_format,
+      min_size,
+      max_size,
+      newer_than,
+      older_than,
+      roots: matches.values_of("ROOTS")
+        .map(|roots| roots.map(PathBuf::from).collect())
+        .unwrap_or_else(|| vec![PathBuf::from(".")]),
+      extensions: matches.values_of("extension")
+        .map(|exts| exts.map(ToOwned::to_owned).collect())
+        .unwrap_or_default(),
+      count: matches.is_present("count"),
+      count_limit: matches.is_present("count-limit"),
+      prune: matches.is_present("prune"),
+      channel_buffer,
+      skip_permission_errors: !matches.is_present("no-skip-permission-errors"),
+      output_file: matches.value_of("output-file").map(PathBuf::from),
+      deduplicate: matches.is_present("deduplicate"),
+      verbose: matches.is_present("verbose"),
+      index_output: matches.value_of("index-output").map(PathBuf::from),
+      index_input: matches.value_of("index-input").map(PathBuf::from),
+      stats: matches.is_present("stats"),
+      color,
+      progress: matches.is_present("progress"),
+      relative_to,
+      sample,
+      exec: matches.values_of("exec").map(|args| args.map(ToOwned::to_owned).collect()),
+      exec_parallel: matches.is_present("parallel"),
     })
   }
 
   pub fn run(&mut self) -> Result<(), FilesError> {
-    let root = env::current_dir()?;
+    let start = Instant::now();
+
+    if let Some(ref path) = self.index_input {
+      let file = fs::File::open(path).with_context(|| path.display().to_string())?;
+      let paths = index::read_index(BufReader::new(file))
+        .with_context(|| path.display().to_string())?;
+      let mut out = self.open_output()?;
+      return self.query_index(&mut out, paths);
+    }
 
-    for entry in self.files(&root)
-      .into_iter()
-      .filter(|entry| !self.matchre.is_some() || is_match(&entry.path(), &self.matchre))
-      .take(self.max_items) {
+    let mut roots = Vec::with_capacity(self.roots.len());
+    for root in &self.roots {
+      roots.push(Root {
+        path: root.clone(),
+        canonical: fs::canonicalize(root).with_context(|| root.display().to_string())?,
+      });
+    }
+
+    // The output file is truncated only after the inputs are validated.
+    let mut out = self.open_output()?;
+
+    // In async (or parallel) mode, all roots are scanned concurrently.
+    // Otherwise, each root is scanned after the previous one is completed.
+    let receivers: Vec<_> = roots.iter()
+      .map(|root| if self.is_async || self.parallel_rayon {
+        Some(self.files(&root.canonical))
+      } else {
+        None
+      })
+      .collect();
+
+    // The hashes of the contents of the files shown so far, for `--deduplicate`.
+    let mut seen = HashSet::new();
+    // The number of the entries shown so far in each directory, for `--limit-per-dir`.
+    let mut counts = HashMap::new();
+    let mut stats = ScanStats::default();
+    let mut progress = Progress::new(self.progress);
+
+    let entries = roots.iter()
+      .zip(receivers)
+      .flat_map(|(root, rx)| {
+        let rx = rx.unwrap_or_else(|| self.files(&root.canonical));
+        // The errors in the traversal are reported, but do not stop the output.
+        rx.into_iter().filter_map(move |entry| match entry {
+          Ok(entry) => Some((root, entry)),
+          Err(e) => {
+            eprintln!("error: {}", e);
+            None
+          }
+        })
+      })
+      .inspect(|_| progress.tick());
+
+    // With `--skip-empty-dirs`, the directories can be checked only after all of the files
+    // are found. Otherwise, the entries are streamed.
+    let (streamed, buffered) = if self.skip_empty_dirs {
+      (None, Some(skip_empty_dirs(entries, self.follow_symlinks)))
+    } else {
+      (Some(entries), None)
+    };
+    let entries = streamed.into_iter()
+      .flatten()
+      .chain(buffered.into_iter().flatten())
+      .filter(|&(root, ref entry)| self.is_selected(root, entry))
+      .filter(|(_, entry)| !self.deduplicate || self.is_first_content(entry, &mut seen))
+      .filter(|(_, entry)| self.is_within_dir_limit(entry, &mut counts))
+      .inspect(|(_, entry)| if self.stats {
+        stats.update(entry)
+      });
+
+    if let Some(ref path) = self.index_output {
+      let mut paths = Vec::new();
+      for (root, entry) in entries {
+        paths.push(self.display_path(root, &entry)?);
+      }
+      let file = fs::File::create(path).with_context(|| path.display().to_string())?;
+      index::write_index(paths, BufWriter::new(file)).with_context(|| path.display().to_string())?;
+      self.report(&stats, &progress, start);
+      return Ok(());
+    }
+
+    if let Some(ref exec) = self.exec {
+      // The commands are run in the order the paths would be printed.
+      let mut paths = Vec::new();
+      for (root, entry) in self.ordered_entries(entries) {
+        paths.push(self.display_path(root, &entry)?);
+      }
+      let failed = self.exec_commands(exec, &paths)?;
+      self.report(&stats, &progress, start);
+      if failed > 0 {
+        return Err(format!("{} of {} commands failed", failed, paths.len()).into());
+      }
+      return Ok(());
+    }
 
-      if self.is_absolute {
-        println!("{}", entry.path().display());
+    if self.count {
+      let count = if self.count_limit {
+        entries.take(self.max_items).count()
       } else {
-        println!("./{}",
-                 entry.path()
-                   .strip_prefix(&root)?
-                   .display());
+        entries.count()
+      };
+      writeln!(out, "{}", count)?;
+      out.flush()?;
+      self.report(&stats, &progress, start);
+      return Ok(());
+    }
+
+    if self.output_format == OutputFormat::Tree {
+      // The tree can be printed only after all entries are collected.
+      let entries = self.ordered_entries(entries);
+      self.print_tree(&mut out, &roots, &entries)?;
+      out.flush()?;
+      self.report(&stats, &progress, start);
+      return Ok(());
+    }
+
+    if self.sort.is_some() || self.sample.is_some() {
+      for (i, (root, entry)) in self.ordered_entries(entries).into_iter().enumerate() {
+        self.print_entry(&mut out, root, &entry, i == 0)?;
+      }
+    } else {
+      for (i, (root, entry)) in entries.take(self.max_items).enumerate() {
+        self.print_entry(&mut out, root, &entry, i == 0)?;
       }
     }
 
+    out.flush()?;
+    self.report(&stats, &progress, start);
     Ok(())
   }
 
-  // Scan all files/directories under given directory synchronously
-  fn files<P: Into<PathBuf>>(&self, root: P) -> mpsc::Receiver<fs::DirEntry> {
-    let root = root.into();
-    let ignore = self.ignore.clone();
-    let is_dir = self.is_directory;
-    let is_async = self.is_async;
+  // Apply `--sample`, `--sort` and `--max-items` to the entries, in the order of the output.
+  fn ordered_entries<'a, I>(&self, entries: I) -> Vec<(&'a Root, fs::DirEntry)>
+    where I: Iterator<Item = (&'a Root, fs::DirEntry)>
+  {
+    if let Some(n) = self.sample {
+      let mut entries = reservoir_sample(entries.take(self.pool_size()), n);
+      if let Some(key) = self.sort {
+        sort_entries(&mut entries, key, self.fuzzy.as_deref());
+      }
+      return entries;
+    }
+    match self.sort {
+      Some(key) => {
+        // All entries have to be buffered before sorting.
+        let mut entries: Vec<_> = entries.collect();
+        sort_entries(&mut entries, key, self.fuzzy.as_deref());
+        entries.truncate(self.max_items);
+        entries
+      }
+      None => entries.take(self.max_items).collect(),
+    }
+  }
 
-    let (tx, rx) = mpsc::sync_channel(20);
-    thread::spawn(move || Self::files_inner(&root, tx, ignore, is_dir, is_async));
+  // Open the file given by `--output-file`, or stdout.
+  fn open_output(&self) -> Result<Box<dyn Write>, FilesError> {
+    match self.output_file {
+      Some(ref path) => {
+        let file = OpenOptions::new()
+          .write(true)
+          .create(true)
+          .truncate(true)
+          .open(path)
+          .with_context(|| path.display().to_string())?;
+        Ok(Box::new(BufWriter::new(file)))
+      }
+      None => Ok(Box::new(io::stdout())),
+    }
+  }
+
+  // Run the command of `--exec` for each path, and return the number of the failed ones.
+  fn exec_commands(&self, exec: &[String], paths: &[PathBuf]) -> Result<usize, FilesError> {
+    let (name, args) = match exec.split_first() {
+      Some(split) => split,
+      None => return Err("the command of --exec is empty".to_owned().into()),
+    };
 
-    rx
+    let mut failed = 0;
+    let mut children = Vec::new();
+    for path in paths {
+      let path = path.to_string_lossy();
+      let args: Vec<String> = args.iter().map(|arg| arg.replace("{}", &path)).collect();
+      let mut command = command::build_command(name, &args, self.exec_parallel);
+      // A command which cannot be run is counted as failed, and the others are still run.
+      if self.exec_parallel {
+        match command.spawn() {
+          Ok(child) => children.push(child),
+          Err(e) => {
+            eprintln!("{}: {}", name, e);
+            failed += 1;
+          }
+        }
+      } else {
+        match command.status() {
+          Ok(status) if status.success() => (),
+          Ok(_) => failed += 1,
+          Err(e) => {
+            eprintln!("{}: {}", name, e);
+            failed += 1;
+          }
+        }
+      }
+    }
+    for mut child in children {
+      match child.wait() {
+        Ok(status) if status.success() => (),
+        Ok(_) => failed += 1,
+        Err(e) => {
+          eprintln!("{}: {}", name, e);
+          failed += 1;
+        }
+      }
+    }
+    Ok(failed)
   }
 
-  fn files_inner(entry: &Path,
-                 tx: mpsc::SyncSender<fs::DirEntry>,
-                 ignore: Arc<Option<regex::Regex>>,
-                 is_dir: bool,
-                 is_async: bool)
-                 -> Result<(), FilesError> {
-    if is_match(&entry, ignore.deref()) {
-      return Ok(());
+  fn report(&self, stats: &ScanStats, progress: &Progress, start: Instant) {
+    progress.finish();
+    if self.stats {
+      eprintln!("Found {} files, {} directories, {} bytes total in {:.2}s",
+                stats.files,
+                stats.dirs,
+                stats.bytes,
+                start.elapsed().as_secs_f64());
     }
+  }
 
-    for entry in std::fs::read_dir(entry)? {
-      let entry = entry?;
-      if !entry.path().is_dir() {
-        if !is_dir && !is_match(&entry.path(), ignore.deref()) {
-          tx.send(entry).unwrap();
+  // Print the paths in the index which are selected by the patterns.
+  //
+  // Only the filters on the names are applied, since the files are not accessed.
+  fn query_index<W: Write>(&self, out: &mut W, paths: Vec<PathBuf>) -> Result<(), FilesError> {
+    let paths = paths.into_iter()
+      .filter(|path| !path.iter().any(|name| self.ignore.matches(Path::new(name))))
+      .filter(|path| self.matchre.is_empty() || self.matchre.matches(path))
+      .filter(|path| self.glob.is_empty() || self.glob.matches(path))
+      .filter(|path| self.extensions.is_empty() || has_extension(path, &self.extensions));
+    for (i, path) in paths.take(self.max_items).enumerate() {
+      if self.output_format == OutputFormat::Nul {
+        if i > 0 {
+          out.write_all(b"\0")?;
         }
-
+        write!(out, "{}", path.display())?;
       } else {
-        let path = entry.path().to_owned();
-        let tx = tx.clone();
-        let ignore = ignore.clone();
+        writeln!(out, "{}", path.display())?;
+      }
+    }
+    out.flush()?;
+    Ok(())
+  }
+
+  // The number of entries the traversal can stop after.
+  //
+  // The limit cannot be applied when some of the entries may be dropped after the traversal,
+  // or when all of the entries are needed.
+  fn walk_limit(&self) -> usize {
+    let is_filtered = !self.matchre.is_empty() || !self.glob.is_empty() ||
+                      self.fuzzy.is_some() || !self.extensions.is_empty() ||
+                      self.min_size.is_some() || self.max_size.is_some() ||
+                      self.newer_than.is_some() || self.older_than.is_some() ||
+                      self.content_match.is_some() || self.deduplicate ||
+                      self.limit_per_dir.is_some();
+    // The sample is sorted after it is drawn, so the sort does not need all entries.
+    let needs_all = (self.sort.is_some() && self.sample.is_none()) ||
+                    (self.count && !self.count_limit) || self.skip_empty_dirs;
+    if is_filtered || needs_all {
+      usize::MAX
+    } else {
+      self.pool_size()
+    }
+  }
+
+  // The number of entries which are read from the traversal, and sampled from with `--sample`.
+  //
+  // Up to 10 times as many entries as the sample size are read, rather than all of them.
+  fn pool_size(&self) -> usize {
+    match self.sample {
+      Some(n) => n.saturating_mul(10).min(self.max_items),
+      None => self.max_items,
+    }
+  }
+
+  fn is_selected(&self, root: &Root, entry: &fs::DirEntry) -> bool {
+    if !self.matchre.is_empty() && !self.matchre.matches(&entry.path()) {
+      return false;
+    }
+
+    if !self.glob.is_empty() {
+      let path = entry.path();
+      let relative = path.strip_prefix(&root.canonical).unwrap_or(&path);
+      if !self.glob.matches(relative) {
+        return false;
+      }
+    }
+
+    if let Some(ref query) = self.fuzzy {
+      if fuzzy_score(&entry.file_name().to_string_lossy(), query).is_none() {
+        return false;
+      }
+    }
+
+    if !self.extensions.is_empty() && !has_extension(&entry.path(), &self.extensions) {
+      return false;
+    }
 
-        if is_dir {
-          tx.send(entry).unwrap();
+    if let Some(ref re) = self.content_match {
+      let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+      if !is_dir && !content_matches(&entry.path(), re) {
+        return false;
+      }
+    }
+
+    if self.min_size.is_none() && self.max_size.is_none() && self.newer_than.is_none() &&
+       self.older_than.is_none() {
+      return true;
+    }
+
+    let metadata = match entry.metadata() {
+      Ok(metadata) => metadata,
+      Err(_) => return false,
+    };
+
+    // The size of directories is meaningless, so they are not filtered.
+    if !metadata.is_dir() {
+      let size = metadata.len();
+      if self.min_size.is_some_and(|min| size < min) ||
+         self.max_size.is_some_and(|max| size > max) {
+        return false;
+      }
+    }
+
+    if self.newer_than.is_some() || self.older_than.is_some() {
+      let mtime = match metadata.modified() {
+        Ok(mtime) => mtime,
+        Err(_) => return false,
+      };
+      if self.newer_than.is_some_and(|t| mtime <= t) ||
+         self.older_than.is_some_and(|t| mtime >= t) {
+        return false;
+      }
+    }
+
+    true
+  }
+
+  // Return whether no file with the same content as the entry has been seen.
+  //
+  // Directories, large files and unreadable files are always treated as unique.
+  fn is_first_content(&self, entry: &fs::DirEntry, seen: &mut HashSet<[u8; 32]>) -> bool {
+    if entry.file_type().map(|t| t.is_dir()).unwrap_or(true) {
+      return true;
+    }
+    let hash = match content_hash(&entry.path()) {
+      Ok(Some(hash)) => hash,
+      Ok(None) | Err(_) => return true,
+    };
+    if seen.insert(hash) {
+      return true;
+    }
+    if self.verbose {
+      eprintln!("skipped duplicate: {}", entry.path().display());
+    }
+    false
+  }
+
+  // Count the entry in its parent directory, and return whether the limit is not exceeded.
+  fn is_within_dir_limit(&self,
+                         entry: &fs::DirEntry,
+                         counts: &mut HashMap<PathBuf, usize>)
+                         -> bool {
+    let limit = match self.limit_per_dir {
+      Some(limit) => limit,
+      None => return true,
+    };
+    let path = entry.path();
+    let parent = path.parent().map(ToOwned::to_owned).unwrap_or_default();
+    let count = counts.entry(parent).or_insert(0);
+    if *count >= limit {
+      return false;
+    }
+    *count += 1;
+    true
+  }
+
+  // Print an entry. `is_first` is set for the first entry, which has no separator before it.
+  fn print_entry<W: Write>(&self,
+                           out: &mut W,
+                           root: &Root,
+                           entry: &fs::DirEntry,
+                           is_first: bool)
+                           -> Result<(), FilesError> {
+    let path = self.display_path(root, entry)?;
+
+    match self.output_format {
+      OutputFormat::Text | OutputFormat::Tree if self.output_metadata => {
+        let metadata =
+          match skip_permission_error(entry.metadata(), &path, self.skip_permission_errors)? {
+            Some(metadata) => metadata,
+            None => return Ok(()),
+          };
+        writeln!(out, "{}\t{}\t{}", path.display(), metadata.len(), mtime_secs(&metadata)?)?;
+      }
+      OutputFormat::Text | OutputFormat::Tree if self.color => {
+        let path = path.to_string_lossy();
+        let range = self.matchre.find(&entry.path()).map(|range| {
+          // The range is in the file name, which is placed at the end of the path.
+          let offset = path.len() - entry.file_name().to_string_lossy().len();
+          range.start + offset..range.end + offset
+        });
+        writeln!(out, "{}", colorize(&path, range))?;
+      }
+      OutputFormat::Text | OutputFormat::Tree => writeln!(out, "{}", path.display())?,
+      // NUL separates the paths, rather than terminates each of them.
+      OutputFormat::Nul => {
+        if !is_first {
+          out.write_all(b"\0")?;
         }
+        write!(out, "{}", path.display())?;
+      }
+      OutputFormat::Json => {
+        let metadata =
+          match skip_permission_error(entry.metadata(), &path, self.skip_permission_errors)? {
+            Some(metadata) => metadata,
+            None => return Ok(()),
+          };
+        let mtime = mtime_secs(&metadata)?;
 
-        if is_async {
-          thread::spawn(move || Self::files_inner(&path, tx, ignore, is_dir, is_async).unwrap());
-        } else {
-          Self::files_inner(&path, tx, ignore, is_dir, is_async)?;
+        let mut object = BTreeMap::new();
+        object.insert("path".to_owned(), Json::String(path.display().to_string()));
+        object.insert("size".to_owned(), Json::U64(metadata.len()));
+        object.insert("mtime".to_owned(), Json::U64(mtime));
+        object.insert("is_dir".to_owned(), Json::Boolean(metadata.is_dir()));
+        writeln!(out, "{}", Json::Object(object))?;
+      }
+    }
+    Ok(())
+  }
+
+  // The path of the entry to be shown.
+  fn display_path(&self, root: &Root, entry: &fs::DirEntry) -> Result<PathBuf, FilesError> {
+    if let Some(ref base) = self.relative_to {
+      let path = entry.path();
+      return match path.strip_prefix(base) {
+        Ok(relative) => Ok(relative.to_owned()),
+        Err(_) => {
+          eprintln!("warning: {} is not under {}", path.display(), base.display());
+          Ok(path.clone())
         }
+      };
+    }
+    if self.is_absolute {
+      Ok(entry.path())
+    } else {
+      Ok(root.path.join(entry.path().strip_prefix(&root.canonical)?))
+    }
+  }
+
+  // Print the entries as a tree under each root.
+  fn print_tree<W: Write>(&self,
+                          out: &mut W,
+                          roots: &[Root],
+                          entries: &[(&Root, fs::DirEntry)])
+                          -> Result<(), FilesError> {
+    // The children of each directory, including the ones of the intermediate directories.
+    let mut tree: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for &(root, ref entry) in entries {
+      let top = self.display_root(root);
+      let mut path = self.display_path(root, entry)?;
+      while path != top {
+        let parent = match path.parent() {
+          Some(parent) => parent.to_owned(),
+          None => break,
+        };
+        tree.entry(parent.clone()).or_default().push(path);
+        path = parent;
       }
     }
+    for children in tree.values_mut() {
+      children.sort();
+      children.dedup();
+    }
 
+    for root in roots {
+      let top = self.display_root(root);
+      // The root is empty if it is the base of `--relative-to`.
+      if top.as_os_str().is_empty() {
+        writeln!(out, ".")?;
+      } else {
+        writeln!(out, "{}", top.display())?;
+      }
+      print_subtree(out, &tree, &top, "")?;
+    }
     Ok(())
   }
+
+  fn display_root(&self, root: &Root) -> PathBuf {
+    if let Some(ref base) = self.relative_to {
+      return match root.canonical.strip_prefix(base) {
+        Ok(relative) => relative.to_owned(),
+        Err(_) => root.canonical.clone(),
+      };
+    }
+    if self.is_absolute {
+      root.canonical.clone()
+    } else {
+      root.path.clone()
+    }
+  }
+
+  // Scan all files/directories under given directory in background.
+  fn files<P: Into<PathBuf>>(&self, root: P) -> Receiver<WalkResult> {
+    traversal::walk(root,
+                    &WalkOptions {
+                      ignore: self.ignore.clone(),
+                      is_directory: self.is_directory,
+                      include_files: !self.is_directory || self.skip_empty_dirs,
+                      is_async: self.is_async,
+                      parallel_rayon: self.parallel_rayon,
+                      max_depth: self.max_depth,
+                      follow_symlinks: self.follow_symlinks,
+                      gitignore: self.gitignore,
+                      prune: self.prune,
+                      skip_permission_errors: self.skip_permission_errors,
+                      cross_fs: self.cross_fs,
+                      limit: self.walk_limit(),
+                      channel_buffer: self.channel_buffer,
+                    })
+  }
+}
+
+// The progress is printed every this number of entries, or after this interval (in ms).
+const PROGRESS_STEP: usize = 1000;
+const PROGRESS_INTERVAL: u64 = 500;
+
+// The running count of the found entries, for `--progress`.
+#[derive(Debug)]
+struct Progress {
+  enabled: bool,
+  count: usize,
+  last_print: Instant,
+}
+
+impl Progress {
+  fn new(enabled: bool) -> Progress {
+    Progress {
+      enabled,
+      count: 0,
+      last_print: Instant::now(),
+    }
+  }
+
+  fn tick(&mut self) {
+    self.count += 1;
+    if !self.enabled {
+      return;
+    }
+    let now = Instant::now();
+    if self.count.is_multiple_of(PROGRESS_STEP) ||
+       now.duration_since(self.last_print) >= Duration::from_millis(PROGRESS_INTERVAL) {
+      eprint!("\r{} entries found...", self.count);
+      self.last_print = now;
+    }
+  }
+
+  // Overwrite the line with the final count.
+  fn finish(&self) {
+    if self.enabled {
+      eprintln!("\r{} entries found   ", self.count);
+    }
+  }
 }
 
-fn is_match(entry: &Path, pattern: &Option<regex::Regex>) -> bool {
-  match *pattern {
-    Some(ref pattern) => {
-      let filename = entry.file_name()
-        .unwrap()
-        .to_string_lossy();
-      pattern.is_match(filename.borrow())
+// The summary of the found entries, for `--stats`.
+#[derive(Debug, Default)]
+struct ScanStats {
+  files: usize,
+  dirs: usize,
+  bytes: u64,
+}
+
+impl ScanStats {
+  fn update(&mut self, entry: &fs::DirEntry) {
+    let metadata = match entry.metadata() {
+      Ok(metadata) => metadata,
+      Err(_) => return,
+    };
+    if metadata.is_dir() {
+      self.dirs += 1;
+    } else {
+      self.files += 1;
+      self.bytes += metadata.len();
     }
-    None => false,
   }
 }
 
+// Make the regular expression case-insensitive if `ignore_case` is set.
+// The empty pattern is kept as is, since it means "no pattern".
+fn case_pattern(pattern: &str, ignore_case: bool) -> Cow<'_, str> {
+  if ignore_case && !pattern.is_empty() {
+    format!("(?i){}", pattern).into()
+  } else {
+    pattern.into()
+  }
+}
+
+const ANSI_RESET: &str = "\x1b[0m";
+const ANSI_DIR: &str = "\x1b[34m";
+const ANSI_MATCH: &str = "\x1b[1;31m";
+
+// Wrap the matched part of the path in the ANSI escape codes.
+//
+// Without a match, the directory part is colored instead to make the file name stand out.
+fn colorize(path: &str, match_range: Option<Range<usize>>) -> String {
+  match match_range {
+    Some(range) => {
+      format!("{}{}{}{}{}",
+              &path[..range.start],
+              ANSI_MATCH,
+              &path[range.clone()],
+              ANSI_RESET,
+              &path[range.end..])
+    }
+    None => {
+      match path.rfind(['/', MAIN_SEPARATOR]) {
+        Some(i) => format!("{}{}{}{}", ANSI_DIR, &path[..i + 1], ANSI_RESET, &path[i + 1..]),
+        None => path.to_owned(),
+      }
+    }
+  }
+}
+
+// Parse a size in bytes, with an optional suffix of K, M or G (powers of 1024).
+fn parse_size(s: &str) -> Result<u64, FilesError> {
+  let (digits, unit) = match s.chars().last() {
+    Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+    Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+    Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+    _ => (s, 1),
+  };
+  let size: u64 = digits.parse().map_err(|_| format!("invalid size: {}", s))?;
+  size.checked_mul(unit).ok_or_else(|| format!("too large size: {}", s).into())
+}
+
+// Parse a date time in RFC 3339 (e.g. `2024-01-01T00:00:00Z`).
+fn parse_datetime(s: &str) -> Result<SystemTime, FilesError> {
+  let datetime = chrono::DateTime::parse_from_rfc3339(s)
+    .map_err(|e| format!("invalid date time '{}': {}", s, e))?;
+  Ok(SystemTime::from(datetime))
+}
+
+// The modification time in seconds since the Unix epoch, or 0 if it is before the epoch.
+fn mtime_secs(metadata: &fs::Metadata) -> io::Result<u64> {
+  Ok(metadata.modified()?
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0))
+}
+
+// Drop the directories which have no file under them, and the files themselves.
+fn skip_empty_dirs<'a, I>(entries: I, follow_symlinks: bool) -> Vec<(&'a Root, fs::DirEntry)>
+  where I: Iterator<Item = (&'a Root, fs::DirEntry)>
+{
+  let mut dirs = Vec::new();
+  let mut nonempty = HashSet::new();
+  for (root, entry) in entries {
+    let is_dir = if follow_symlinks {
+      entry.path().is_dir()
+    } else {
+      entry.file_type().map(|t| t.is_dir()).unwrap_or(false)
+    };
+    if is_dir {
+      dirs.push((root, entry));
+      continue;
+    }
+
+    let path = entry.path();
+    for dir in path.ancestors().skip(1) {
+      // The ancestors of a marked directory are already marked.
+      if !nonempty.insert(dir.to_owned()) {
+        break;
+      }
+    }
+  }
+
+  dirs.into_iter().filter(|(_, entry)| nonempty.contains(&entry.path())).collect()
+}
+
+// Sort the entries by the key. `query` is the query of `--fuzzy`, used for `SortKey::Score`.
+fn sort_entries<R>(entries: &mut [(R, fs::DirEntry)], key: SortKey, query: Option<&str>) {
+  match key {
+    SortKey::Name => entries.sort_by_key(|(_, entry)| entry.path()),
+    SortKey::Size => {
+      entries.sort_by_key(|(_, entry)| entry.metadata().map(|m| m.len()).unwrap_or(0))
+    }
+    SortKey::Mtime => {
+      entries.sort_by_key(|(_, entry)| entry.metadata().and_then(|m| m.modified()).ok())
+    }
+    SortKey::Score => {
+      let query = query.unwrap_or("");
+      entries.sort_by_key(|(_, entry)| {
+        Reverse(fuzzy_score(&entry.file_name().to_string_lossy(), query))
+      })
+    }
+  }
+}
+
+// Score how well the haystack matches the needle, case-insensitively.
+//
+// All characters of the needle must appear in the haystack in order, otherwise `None` is
+// returned. Each matched character scores 1, and 2 more if it follows the previous match.
+fn fuzzy_score(haystack: &str, needle: &str) -> Option<u32> {
+  let mut haystack = haystack.chars().flat_map(char::to_lowercase).enumerate();
+  let mut score = 0;
+  let mut last = None;
+  for c in needle.chars().flat_map(char::to_lowercase) {
+    let i = match haystack.find(|&(_, h)| h == c) {
+      Some((i, _)) => i,
+      None => return None,
+    };
+    score += if last.is_some_and(|last| last + 1 == i) { 3 } else { 1 };
+    last = Some(i);
+  }
+  Some(score)
+}
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+  let ext = match path.extension() {
+    Some(ext) => ext.to_string_lossy(),
+    None => return false,
+  };
+  extensions.iter().any(|e| if cfg!(unix) {
+    *e == ext
+  } else {
+    e.eq_ignore_ascii_case(&ext)
+  })
+}
+
+// Only this size of the beginning of each file is scanned for `--content-match`.
+const MAX_SCANNED_SIZE: u64 = 1024 * 1024;
+
+// Return whether a line of the file matches the pattern.
+//
+// Unreadable files and binary files (which contain NUL or invalid UTF-8) never match.
+fn content_matches(path: &Path, re: &regex::Regex) -> bool {
+  let file = match fs::File::open(path) {
+    Ok(file) => file,
+    Err(_) => return false,
+  };
+  for line in BufReader::new(file.take(MAX_SCANNED_SIZE)).lines() {
+    let line = match line {
+      Ok(line) => line,
+      Err(_) => return false,
+    };
+    if line.contains('\0') {
+      return false;
+    }
+    if re.is_match(&line) {
+      return true;
+    }
+  }
+  false
+}
+
+// The files larger than this are not hashed for `--deduplicate`.
+const MAX_HASHED_SIZE: u64 = 4 * 1024 * 1024;
+
+// Choose `n` items at random from the iterator, with the reservoir sampling (Algorithm R).
+//
+// All of the items are returned if there are fewer than `n`.
+fn reservoir_sample<T, I: Iterator<Item = T>>(items: I, n: usize) -> Vec<T> {
+  let mut rng = rand::thread_rng();
+  let mut reservoir = Vec::new();
+  for (i, item) in items.enumerate() {
+    if i < n {
+      reservoir.push(item);
+    } else {
+      let j = rng.gen_range(0..i + 1);
+      if j < n {
+        reservoir[j] = item;
+      }
+    }
+  }
+  reservoir
+}
+
+// Compute the SHA-256 of the content of the file, or `None` if it is too large.
+//
+// The file is read rather than memory-mapped, so that it cannot be changed while hashing.
+fn content_hash(path: &Path) -> io::Result<Option<[u8; 32]>> {
+  let mut file = fs::File::open(path)?;
+  let mut hasher = Sha256::new();
+  let mut buf = [0u8; 64 * 1024];
+  let mut total = 0;
+  loop {
+    let len = file.read(&mut buf)?;
+    if len == 0 {
+      break;
+    }
+    total += len as u64;
+    if total > MAX_HASHED_SIZE {
+      return Ok(None);
+    }
+    hasher.update(&buf[..len]);
+  }
+
+  let mut hash = [0u8; 32];
+  hash.copy_from_slice(&hasher.finalize());
+  Ok(Some(hash))
+}
+
+// Print the children of `dir` with the box-drawing characters, recursively.
+fn print_subtree<W: Write>(out: &mut W,
+                           tree: &BTreeMap<PathBuf, Vec<PathBuf>>,
+                           dir: &Path,
+                           prefix: &str)
+                           -> io::Result<()> {
+  let children = match tree.get(dir) {
+    Some(children) => children,
+    None => return Ok(()),
+  };
+  for (i, child) in children.iter().enumerate() {
+    let is_last = i + 1 == children.len();
+    let name = child.file_name().map_or_else(|| child.to_string_lossy(), |s| s.to_string_lossy());
+    writeln!(out, "{}{} {}", prefix, if is_last { "└──" } else { "├──" }, name)?;
+    let prefix = format!("{}{}", prefix, if is_last { "    " } else { "│   " });
+    print_subtree(out, tree, child, &prefix)?;
+  }
+  Ok(())
+}
+
 fn _main() -> Result<(), FilesError> {
-  Ok(Cli::new()?.run()?)
+  Cli::new()?.run()
 }
 
 fn main() {
-  _main().unwrap_or_else(|e| panic!("error: {:?}", e));
+  _main().unwrap_or_else(|e| panic!("error: {}", e));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::iter;
+
+  // Run `files` on the root with the arguments, and return the output.
+  fn run_files(root: &Path, args: &[&str]) -> String {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("output");
+    let argv = iter::once("files")
+      .chain(args.iter().cloned())
+      .chain(vec!["-o", output.to_str().unwrap(), root.to_str().unwrap()]);
+    let mut cli = Cli::from_matches(&Cli::build_app().get_matches_from(argv)).unwrap();
+    cli.run().unwrap();
+    fs::read_to_string(&output).unwrap()
+  }
+
+  // Run `files` on the root with the arguments, and return the output paths relative to it.
+  fn files(root: &Path, args: &[&str]) -> Vec<String> {
+    let mut paths: Vec<String> = run_files(root, args)
+      .lines()
+      .map(|line| Path::new(line).strip_prefix(root).unwrap().to_string_lossy().into_owned())
+      .collect();
+    paths.sort();
+    paths
+  }
+
+  // The tree used by the tests below: .env, .git/objects/x and src/a.rs
+  fn fixture() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join(".git/objects")).unwrap();
+    fs::create_dir_all(dir.path().join("src")).unwrap();
+    for name in &[".env", ".git/objects/x", "src/a.rs"] {
+      fs::write(dir.path().join(name), "").unwrap();
+    }
+    dir
+  }
+
+  #[test]
+  fn hidden_files_are_shown_only_with_hidden() {
+    let dir = fixture();
+    assert_eq!(files(dir.path(), &[]), vec!["src/a.rs"]);
+    assert_eq!(files(dir.path(), &["--hidden"]), vec![".env", "src/a.rs"]);
+  }
+
+  #[test]
+  fn no_vcs_ignore_shows_the_vcs_directories() {
+    let dir = fixture();
+    assert_eq!(files(dir.path(), &["--no-vcs-ignore"]), vec![".git/objects/x", "src/a.rs"]);
+    assert_eq!(files(dir.path(), &["--no-vcs-ignore", "--hidden"]),
+               vec![".env", ".git/objects/x", "src/a.rs"]);
+    assert_eq!(files(dir.path(), &["--hidden"]), vec![".env", "src/a.rs"]);
+  }
+
+  #[test]
+  fn tree_output() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("a/c")).unwrap();
+    for name in &["a/b.txt", "a/c/d.txt", "e.txt"] {
+      fs::write(dir.path().join(name), "").unwrap();
+    }
+
+    let root = dir.path().to_str().unwrap();
+    let output = run_files(dir.path(), &["--output-format", "tree", "--relative-to", root]);
+    assert_eq!(output,
+               ".\n\
+                ├── a\n\
+                │   ├── b.txt\n\
+                │   └── c\n\
+                │       └── d.txt\n\
+                └── e.txt\n");
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn exec_runs_the_commands_in_the_sorted_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    for name in &["c", "a", "b"] {
+      fs::write(root.join(name), "").unwrap();
+    }
+
+    // `echo {}` appends each path to the log, as the output of the commands is not captured.
+    let log = dir.path().join("log");
+    let script = format!("echo \"$0\" >> {}", log.display());
+    let root_str = root.to_str().unwrap();
+    run_files(&root,
+              &["--sort", "name", "--relative-to", root_str, "--exec", "sh", "-c", &script, "{}",
+                ";"]);
+    assert_eq!(fs::read_to_string(&log).unwrap(), "a\nb\nc\n");
+  }
+
+  #[test]
+  fn exec_keeps_going_after_a_spawn_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in &["a", "b"] {
+      fs::write(dir.path().join(name), "").unwrap();
+    }
+    let argv = vec!["files", "--parallel", "--exec", "no-such-command-{}", ";",
+                    dir.path().to_str().unwrap()];
+    let mut cli = Cli::from_matches(&Cli::build_app().get_matches_from(argv)).unwrap();
+    match cli.run() {
+      Err(FilesError::Other(message)) => assert_eq!(message, "2 of 2 commands failed"),
+      result => panic!("unexpected result: {:?}", result),
+    }
+  }
 }
@@ -0,0 +1,171 @@
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate linked_hash_map;
+#[macro_use]
+extern crate rust_miscs;
+
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+
+use linked_hash_map::LinkedHashMap;
+use rustc_serialize::json::{self, Json};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ArrayNotation {
+  Index,
+  Key,
+}
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_separator: String,
+  flag_array_notation: ArrayNotation,
+}
+
+const USAGE: &str = r"
+Flatten the nested objects in newline-delimited JSON records
+
+Usage:
+  flatten-json [options]
+  flatten-json (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --separator=<CHAR>
+                    The separator of the keys [default: .]
+  --array-notation=<NOTATION>
+                    index (a.0) or key (a[0]) [default: index]
+";
+
+def_error! {
+  FlattenError {
+    Io(io::Error)           => "I/O error: {0}",
+    Json(json::ParserError) => "invalid JSON: {0}",
+    Other(String)           => "{0}",
+  }
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  let stdin = io::stdin();
+  let stdout = io::stdout();
+  if let Err(e) = run(stdin.lock(), stdout.lock(), args) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
+
+fn run<R: BufRead, W: Write>(reader: R, mut out: W, args: Args) -> Result<(), FlattenError> {
+  let mut chars = args.flag_separator.chars();
+  let sep = match (chars.next(), chars.next()) {
+    (Some(c), None) => c,
+    _ => return Err("the separator must be a single character.".to_owned().into()),
+  };
+
+  for (i, line) in reader.lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let record = Json::from_str(&line)?;
+    if !record.is_object() {
+      return Err(format!("line {}: the record is not an object", i + 1).into());
+    }
+    let mut flattened = LinkedHashMap::new();
+    flatten(record, "", sep, args.flag_array_notation, &mut flattened);
+    write_object(&mut out, &flattened)?;
+  }
+
+  out.flush()?;
+  Ok(())
+}
+
+// Insert the leaves of the value into `flattened`, with the keys joined by `sep`.
+//
+// The leaves are kept in the order of traversal, so that the elements of an array are not
+// reordered as strings (`a.10` before `a.2`).
+//
+// The empty objects and arrays are leaves, so that they are not lost.
+fn flatten(value: Json,
+           prefix: &str,
+           sep: char,
+           notation: ArrayNotation,
+           flattened: &mut LinkedHashMap<String, Json>) {
+  match value {
+    Json::Object(object) if !object.is_empty() => {
+      for (key, value) in object {
+        let key = if prefix.is_empty() {
+          key
+        } else {
+          format!("{}{}{}", prefix, sep, key)
+        };
+        flatten(value, &key, sep, notation, flattened);
+      }
+    }
+    Json::Array(array) if !array.is_empty() => {
+      for (i, value) in array.into_iter().enumerate() {
+        let key = match notation {
+          ArrayNotation::Index if prefix.is_empty() => i.to_string(),
+          ArrayNotation::Index => format!("{}{}{}", prefix, sep, i),
+          ArrayNotation::Key => format!("{}[{}]", prefix, i),
+        };
+        flatten(value, &key, sep, notation, flattened);
+      }
+    }
+    value => {
+      flattened.insert(prefix.to_owned(), value);
+    }
+  }
+}
+
+// Write the object as a line of JSON, in the insertion order of the keys.
+//
+// `Json::Object` is a `BTreeMap`, which would sort the keys.
+fn write_object<W: Write>(out: &mut W, object: &LinkedHashMap<String, Json>) -> io::Result<()> {
+  out.write_all(b"{")?;
+  for (i, (key, value)) in object.iter().enumerate() {
+    if i > 0 {
+      out.write_all(b",")?;
+    }
+    write!(out, "{}:{}", Json::String(key.clone()), value)?;
+  }
+  out.write_all(b"}\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn flatten_line(input: &str, notation: ArrayNotation) -> String {
+    let args = Args {
+      flag_separator: ".".to_owned(),
+      flag_array_notation: notation,
+    };
+    let mut out = Vec::new();
+    run(Cursor::new(input), &mut out, args).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn array_elements_keep_their_order() {
+    let input = format!("{{\"a\": [{}]}}\n",
+                        (0..12).map(|i| i.to_string()).collect::<Vec<_>>().join(","));
+    let output = flatten_line(&input, ArrayNotation::Index);
+    let expected: Vec<String> = (0..12).map(|i| format!("\"a.{0}\":{0}", i)).collect();
+    assert_eq!(output, format!("{{{}}}\n", expected.join(",")));
+  }
+
+  #[test]
+  fn empty_containers_are_leaves() {
+    let output = flatten_line(r#"{"a": {"b": [], "c": {}}, "d": ["x"]}"#, ArrayNotation::Key);
+    assert_eq!(output, "{\"a.b\":[],\"a.c\":{},\"d[0]\":\"x\"}\n");
+  }
+}
@@ -0,0 +1,158 @@
+//! A minimal implementation of `.gitignore` rules.
+//!
+//! Supported are the most common forms of patterns: literal names, wildcards (`*`, `?`, `**`
+//! and `[...]`), negation with `!`, and directory-only patterns ending with `/`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+#[derive(Debug, Clone)]
+struct Rule {
+  // The directory where the `.gitignore` is placed.
+  base: PathBuf,
+  pattern: Regex,
+  negated: bool,
+  dir_only: bool,
+  // Whether the pattern is matched against the path relative to `base`, not the file name.
+  anchored: bool,
+}
+
+impl Rule {
+  fn parse(base: &Path, line: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+      return None;
+    }
+
+    let (negated, line) = if let Some(line) = line.strip_prefix('!') {
+      (true, line)
+    } else if line.starts_with("\\!") || line.starts_with("\\#") {
+      (false, &line[1..])
+    } else {
+      (false, line)
+    };
+
+    let (dir_only, line) = match line.strip_suffix('/') {
+      Some(line) => (true, line),
+      None => (false, line),
+    };
+
+    let anchored = line.contains('/');
+    let line = line.trim_start_matches('/');
+    if line.is_empty() {
+      return None;
+    }
+
+    Regex::new(&glob_to_regex(line)).ok().map(|pattern| {
+      Rule {
+        base: base.to_owned(),
+        pattern,
+        negated,
+        dir_only,
+        anchored,
+      }
+    })
+  }
+
+  fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+    if self.dir_only && !is_dir {
+      return false;
+    }
+
+    let target = if self.anchored {
+      path.strip_prefix(&self.base).ok()
+    } else {
+      path.file_name().map(Path::new)
+    };
+    match target {
+      Some(target) => self.pattern.is_match(&target.to_string_lossy().replace('\\', "/")),
+      None => false,
+    }
+  }
+}
+
+/// A set of ignore rules collected from the `.gitignore` files.
+#[derive(Debug, Clone, Default)]
+pub struct Gitignore {
+  rules: Vec<Rule>,
+}
+
+impl Gitignore {
+  pub fn new() -> Gitignore {
+    Gitignore::default()
+  }
+
+  /// Return the rules extended with the `.gitignore` in the directory `dir`, if it exists.
+  ///
+  /// The rules in `dir` take precedence over the ones of the parent directories.
+  pub fn with_dir(&self, dir: &Path) -> io::Result<Gitignore> {
+    let file = match File::open(dir.join(".gitignore")) {
+      Ok(file) => file,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(self.clone()),
+      Err(e) => return Err(e),
+    };
+
+    let mut rules = self.rules.clone();
+    for line in BufReader::new(file).lines() {
+      if let Some(rule) = Rule::parse(dir, &line?) {
+        rules.push(rule);
+      }
+    }
+    Ok(Gitignore { rules })
+  }
+
+  /// Return whether the path is ignored by the rules.
+  pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+    // The last matching rule decides the result.
+    self.rules
+      .iter()
+      .rev()
+      .find(|rule| rule.is_match(path, is_dir))
+      .is_some_and(|rule| !rule.negated)
+  }
+}
+
+// Translate a glob pattern into an anchored regular expression.
+fn glob_to_regex(glob: &str) -> String {
+  let mut re = String::from("^");
+  let mut chars = glob.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '*' if chars.peek() == Some(&'*') => {
+        chars.next();
+        if chars.peek() == Some(&'/') {
+          // `**/` matches zero or more directories.
+          chars.next();
+          re.push_str("(?:.*/)?");
+        } else {
+          re.push_str(".*");
+        }
+      }
+      '*' => re.push_str("[^/]*"),
+      '?' => re.push_str("[^/]"),
+      '[' => {
+        re.push('[');
+        if chars.peek() == Some(&'!') {
+          chars.next();
+          re.push('^');
+        }
+        for c in chars.by_ref() {
+          if c == ']' {
+            break;
+          }
+          if c == '\\' || c == '[' {
+            re.push('\\');
+          }
+          re.push(c);
+        }
+        re.push(']');
+      }
+      c => re.push_str(&::regex::escape(&c.to_string())),
+    }
+  }
+  re.push('$');
+  re
+}
@@ -0,0 +1,114 @@
+//! The index file of paths, which can be queried later without scanning the filesystem.
+//!
+//! The file starts with the magic bytes `FIDX` and a version byte, followed by the paths
+//! encoded as UTF-8, each of which is prefixed with its length (4 bytes, little endian).
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+const MAGIC: &[u8; 4] = b"FIDX";
+const VERSION: u8 = 1;
+
+// The maximum length of a path in the index, far beyond any real path.
+//
+// The lengths read from the index are checked against it before allocating the buffer,
+// so that a broken index cannot make the reader allocate up to 4 GiB per entry.
+const MAX_PATH_LEN: usize = 64 * 1024;
+
+/// Write the paths into the index.
+pub fn write_index<I, W>(paths: I, mut out: W) -> io::Result<()>
+  where I: IntoIterator<Item = PathBuf>,
+        W: Write
+{
+  out.write_all(MAGIC)?;
+  out.write_all(&[VERSION])?;
+  for path in paths {
+    let path = path.to_str()
+      .ok_or_else(|| invalid_data(format!("non UTF-8 path: {}", path.display())))?;
+    if path.len() > MAX_PATH_LEN {
+      return Err(invalid_data(format!("too long path: {}", path)));
+    }
+    out.write_all(&(path.len() as u32).to_le_bytes())?;
+    out.write_all(path.as_bytes())?;
+  }
+  out.flush()
+}
+
+/// Read all of the paths from the index.
+pub fn read_index<R: Read>(mut input: R) -> io::Result<Vec<PathBuf>> {
+  let mut header = [0u8; 5];
+  input.read_exact(&mut header)?;
+  if &header[..4] != MAGIC {
+    return Err(invalid_data("not an index file".to_owned()));
+  }
+  if header[4] != VERSION {
+    return Err(invalid_data(format!("unsupported index version: {}", header[4])));
+  }
+
+  let mut paths = Vec::new();
+  loop {
+    let mut len = [0u8; 4];
+    // The end of file is allowed only at the boundary of the entries.
+    match input.read(&mut len[..1])? {
+      0 => break,
+      _ => input.read_exact(&mut len[1..])?,
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_PATH_LEN {
+      return Err(invalid_data(format!("too long path in the index: {} bytes", len)));
+    }
+    let mut buf = vec![0u8; len];
+    input.read_exact(&mut buf)?;
+    let path = String::from_utf8(buf).map_err(|e| invalid_data(e.to_string()))?;
+    paths.push(PathBuf::from(path));
+  }
+  Ok(paths)
+}
+
+fn invalid_data(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn index_of(paths: &[&str]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_index(paths.iter().map(PathBuf::from), &mut buf).unwrap();
+    buf
+  }
+
+  #[test]
+  fn paths_round_trip() {
+    let paths = read_index(&index_of(&["a", "b/c", ""])[..]).unwrap();
+    assert_eq!(paths, vec![PathBuf::from("a"), PathBuf::from("b/c"), PathBuf::from("")]);
+  }
+
+  #[test]
+  fn truncated_index_is_an_error() {
+    let buf = index_of(&["abc"]);
+    for len in 0..buf.len() {
+      // Only the end of the header is a boundary of the entries.
+      if len == MAGIC.len() + 1 {
+        continue;
+      }
+      assert!(read_index(&buf[..len]).is_err(), "{} bytes", len);
+    }
+  }
+
+  #[test]
+  fn oversized_length_is_an_error() {
+    let mut buf = index_of(&[]);
+    buf.extend_from_slice(&u32::MAX.to_le_bytes());
+    let err = read_index(&buf[..]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn too_long_path_is_not_written() {
+    let path = PathBuf::from("a".repeat(MAX_PATH_LEN + 1));
+    let err = write_index(Some(path), io::sink()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  }
+}
@@ -0,0 +1,131 @@
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate rust_miscs;
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+
+use rustc_serialize::json::{self, Json};
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_select: Option<String>,
+  flag_compact: bool,
+  flag_null_on_missing: bool,
+  flag_array: bool,
+}
+
+const USAGE: &str = r"
+Project the fields of newline-delimited JSON records
+
+Usage:
+  jlines [options]
+  jlines (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --select=<FIELDS>
+                    Emit only the comma-separated FIELDS of each record
+  --compact         Print each record in a single line (default with --select)
+  --null-on-missing
+                    Emit null for the selected fields missing in a record,
+                    instead of omitting them
+  --array           Wrap the output records in a JSON array
+";
+
+def_error! {
+  JlinesError {
+    Io(io::Error)           => "I/O error: {0}",
+    Json(json::ParserError) => "invalid JSON: {0}",
+    Other(String)           => "{0}",
+  }
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  let stdin = io::stdin();
+  let stdout = io::stdout();
+  if let Err(e) = run(stdin.lock(), stdout.lock(), args) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
+
+fn run<R: BufRead, W: Write>(reader: R, mut out: W, args: Args) -> Result<(), JlinesError> {
+  let fields: Option<Vec<String>> = args.flag_select
+    .as_ref()
+    .map(|fields| fields.split(',').map(|field| field.trim().to_owned()).collect());
+  // The projected records are NDJSON, so they are kept in a single line.
+  let compact = args.flag_compact || fields.is_some();
+
+  if args.flag_array {
+    writeln!(out, "[")?;
+  }
+  let mut is_first = true;
+  for (i, line) in reader.lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let record = Json::from_str(&line)?;
+    let record = match fields {
+      Some(ref fields) => {
+        select(record, fields, args.flag_null_on_missing)
+          .map_err(|e| format!("line {}: {}", i + 1, e))?
+      }
+      None => record,
+    };
+
+    if args.flag_array && !is_first {
+      writeln!(out, ",")?;
+    }
+    if compact {
+      write!(out, "{}", record)?;
+    } else {
+      write!(out, "{}", record.pretty())?;
+    }
+    if !args.flag_array {
+      writeln!(out)?;
+    }
+    is_first = false;
+  }
+  if args.flag_array {
+    if !is_first {
+      writeln!(out)?;
+    }
+    writeln!(out, "]")?;
+  }
+
+  out.flush()?;
+  Ok(())
+}
+
+// Build a record which consists only of the given fields of `record`.
+fn select(record: Json, fields: &[String], null_on_missing: bool) -> Result<Json, String> {
+  let mut record = match record {
+    Json::Object(record) => record,
+    _ => return Err("the record is not an object".to_owned()),
+  };
+
+  let mut selected = BTreeMap::new();
+  for field in fields {
+    match record.remove(field) {
+      Some(value) => {
+        selected.insert(field.clone(), value);
+      }
+      None if null_on_missing => {
+        selected.insert(field.clone(), Json::Null);
+      }
+      None => (),
+    }
+  }
+  Ok(Json::Object(selected))
+}
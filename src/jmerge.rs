@@ -0,0 +1,311 @@
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate rust_miscs;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::exit;
+
+use rustc_serialize::json::{self, Json};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JoinType {
+  Inner,
+  Left,
+  Right,
+  Full,
+}
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_key: String,
+  flag_left: String,
+  flag_right: String,
+  flag_join_type: JoinType,
+}
+
+const USAGE: &str = r"
+Merge two streams of newline-delimited JSON records by a common key
+
+Usage:
+  jmerge [options] --key=<FIELD> --left=<FILE> --right=<FILE>
+  jmerge (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --key=<FIELD>     The field whose values are matched between the records
+  --left=<FILE>     The left records ('-' means stdin)
+  --right=<FILE>    The right records ('-' means stdin). The fields of the right
+                    record take precedence over the left one
+  --join-type=<TYPE>
+                    inner, left, right or full [default: inner]
+";
+
+def_error! {
+  JmergeError {
+    Io(io::Error)           => "I/O error: {0}",
+    Json(json::ParserError) => "invalid JSON: {0}",
+    Other(String)           => "{0}",
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+  Left,
+  Right,
+}
+
+impl JoinType {
+  // Whether the unmatched records of the side are emitted as they are.
+  fn keeps(&self, side: Side) -> bool {
+    matches!((*self, side),
+             (JoinType::Full, _) | (JoinType::Left, Side::Left) | (JoinType::Right, Side::Right))
+  }
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  let stdout = io::stdout();
+  if let Err(e) = run(stdout.lock(), args) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
+
+fn run<W: Write>(mut out: W, args: Args) -> Result<(), JmergeError> {
+  if args.flag_left == "-" && args.flag_right == "-" {
+    return Err("only one of --left and --right can be stdin.".to_owned().into());
+  }
+
+  // The smaller input is buffered, and the other one is streamed.
+  // The stdin is always streamed since its size is unknown.
+  let buffered_side = match (file_size(&args.flag_left)?, file_size(&args.flag_right)?) {
+    (None, _) => Side::Right,
+    (_, None) => Side::Left,
+    (Some(left), Some(right)) if left < right => Side::Left,
+    _ => Side::Right,
+  };
+  let (buffered_path, streamed_path) = match buffered_side {
+    Side::Left => (&args.flag_left, &args.flag_right),
+    Side::Right => (&args.flag_right, &args.flag_left),
+  };
+  join(&mut out,
+       open(buffered_path)?,
+       open(streamed_path)?,
+       buffered_side,
+       &args.flag_key,
+       args.flag_join_type)?;
+
+  out.flush()?;
+  Ok(())
+}
+
+// Join the records read from `buffered_reader` and `streamed_reader`, where `buffered_side`
+// tells which side the buffered records come from.
+fn join<W: Write>(out: &mut W,
+                  buffered_reader: Box<dyn BufRead>,
+                  streamed_reader: Box<dyn BufRead>,
+                  buffered_side: Side,
+                  key: &str,
+                  join_type: JoinType)
+                  -> Result<(), JmergeError> {
+  let streamed_side = match buffered_side {
+    Side::Left => Side::Right,
+    Side::Right => Side::Left,
+  };
+
+  // The buffered records grouped by the key, along with the keys in the order of appearance.
+  let mut buffered: HashMap<String, Vec<Json>> = HashMap::new();
+  let mut keys = Vec::new();
+  // The records without the key never match, but are emitted in the outer joins.
+  let mut keyless = Vec::new();
+  for record in read_records(buffered_reader) {
+    let record = record?;
+    match key_of(&record, key) {
+      Some(k) => {
+        if !buffered.contains_key(&k) {
+          keys.push(k.clone());
+        }
+        buffered.entry(k).or_default().push(record);
+      }
+      None => keyless.push(record),
+    }
+  }
+
+  let mut matched = HashSet::new();
+  for record in read_records(streamed_reader) {
+    let record = record?;
+    let partners = key_of(&record, key).and_then(|k| buffered.get(&k).map(|rs| (k, rs)));
+    match partners {
+      Some((k, partners)) => {
+        for partner in partners {
+          let merged = match streamed_side {
+            Side::Left => merge(&record, partner)?,
+            Side::Right => merge(partner, &record)?,
+          };
+          writeln!(out, "{}", merged)?;
+        }
+        matched.insert(k);
+      }
+      None if join_type.keeps(streamed_side) => writeln!(out, "{}", record)?,
+      None => (),
+    }
+  }
+
+  if join_type.keeps(buffered_side) {
+    for k in keys.iter().filter(|k| !matched.contains(*k)) {
+      for record in &buffered[k] {
+        writeln!(out, "{}", record)?;
+      }
+    }
+    for record in &keyless {
+      writeln!(out, "{}", record)?;
+    }
+  }
+
+  Ok(())
+}
+
+// The size of the input file, or `None` for stdin.
+fn file_size(path: &str) -> Result<Option<u64>, JmergeError> {
+  if path == "-" {
+    return Ok(None);
+  }
+  let metadata = fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?;
+  Ok(Some(metadata.len()))
+}
+
+fn open(path: &str) -> Result<Box<dyn BufRead>, JmergeError> {
+  if path == "-" {
+    return Ok(Box::new(BufReader::new(io::stdin())));
+  }
+  let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+  Ok(Box::new(BufReader::new(file)))
+}
+
+fn read_records<'a>(reader: Box<dyn BufRead + 'a>)
+                    -> Box<dyn Iterator<Item = Result<Json, JmergeError>> + 'a> {
+  Box::new(reader.lines()
+    .filter(|line| line.as_ref().map(|line| !line.trim().is_empty()).unwrap_or(true))
+    .map(|line| -> Result<Json, JmergeError> { Ok(Json::from_str(&line?)?) }))
+}
+
+// The value of the key field as a string, which is compared between the records.
+fn key_of(record: &Json, key: &str) -> Option<String> {
+  match record.find(key) {
+    Some(Json::String(s)) => Some(s.clone()),
+    Some(&Json::Null) | None => None,
+    Some(value) => Some(value.to_string()),
+  }
+}
+
+// Merge the fields of the records. The right one takes precedence on conflict.
+fn merge(left: &Json, right: &Json) -> Result<Json, JmergeError> {
+  match (left, right) {
+    (Json::Object(left), Json::Object(right)) => {
+      let mut merged = left.clone();
+      merged.extend(right.iter().map(|(k, v)| (k.clone(), v.clone())));
+      Ok(Json::Object(merged))
+    }
+    _ => Err("the records to be merged must be objects.".to_owned().into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LEFT: &str = r#"{"id":1,"name":"a","v":"left"}
+{"id":2,"name":"b"}
+{"name":"keyless-left"}
+"#;
+
+  const RIGHT: &str = r#"{"id":1,"v":"right"}
+{"id":3,"v":"c"}
+{"v":"keyless-right"}
+"#;
+
+  // Join the records with each side buffered, and check that both give the same lines.
+  fn join_lines(join_type: JoinType) -> Vec<String> {
+    let mut results = Vec::new();
+    for &buffered_side in &[Side::Left, Side::Right] {
+      let (buffered, streamed) = match buffered_side {
+        Side::Left => (LEFT, RIGHT),
+        Side::Right => (RIGHT, LEFT),
+      };
+      let mut out = Vec::new();
+      join(&mut out,
+           Box::new(buffered.as_bytes()),
+           Box::new(streamed.as_bytes()),
+           buffered_side,
+           "id",
+           join_type)
+        .unwrap();
+      let mut lines: Vec<_> = String::from_utf8(out).unwrap()
+        .lines()
+        .map(|line| line.to_owned())
+        .collect();
+      lines.sort();
+      results.push(lines);
+    }
+    assert_eq!(results[0], results[1]);
+    results.pop().unwrap()
+  }
+
+  #[test]
+  fn inner_join() {
+    assert_eq!(join_lines(JoinType::Inner),
+               vec![r#"{"id":1,"name":"a","v":"right"}"#]);
+  }
+
+  #[test]
+  fn left_join() {
+    assert_eq!(join_lines(JoinType::Left),
+               vec![r#"{"id":1,"name":"a","v":"right"}"#,
+                    r#"{"id":2,"name":"b"}"#,
+                    r#"{"name":"keyless-left"}"#]);
+  }
+
+  #[test]
+  fn right_join() {
+    assert_eq!(join_lines(JoinType::Right),
+               vec![r#"{"id":1,"name":"a","v":"right"}"#,
+                    r#"{"id":3,"v":"c"}"#,
+                    r#"{"v":"keyless-right"}"#]);
+  }
+
+  #[test]
+  fn full_join() {
+    assert_eq!(join_lines(JoinType::Full),
+               vec![r#"{"id":1,"name":"a","v":"right"}"#,
+                    r#"{"id":2,"name":"b"}"#,
+                    r#"{"id":3,"v":"c"}"#,
+                    r#"{"name":"keyless-left"}"#,
+                    r#"{"v":"keyless-right"}"#]);
+  }
+
+  #[test]
+  fn right_side_wins_on_conflict() {
+    let left = Json::from_str(r#"{"id":1,"v":"left","l":true}"#).unwrap();
+    let right = Json::from_str(r#"{"id":1,"v":"right","r":true}"#).unwrap();
+    assert_eq!(merge(&left, &right).unwrap().to_string(),
+               r#"{"id":1,"l":true,"r":true,"v":"right"}"#);
+  }
+
+  #[test]
+  fn keyless_records_never_match() {
+    assert_eq!(key_of(&Json::from_str(r#"{"id":null}"#).unwrap(), "id"), None);
+    assert_eq!(key_of(&Json::from_str(r#"{"name":"x"}"#).unwrap(), "id"), None);
+    assert_eq!(key_of(&Json::from_str(r#"{"id":1}"#).unwrap(), "id"),
+               Some("1".to_owned()));
+  }
+}
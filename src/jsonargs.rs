@@ -1,96 +1,996 @@
 extern crate docopt;
 extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate num_cpus;
+extern crate toml;
+extern crate csv;
+extern crate tempfile;
 extern crate handlebars;
+extern crate rust_miscs;
 
 use std::collections::BTreeMap;
-use std::io::{self, BufRead};
-use std::process::{Command, Stdio};
+use std::fs::{self, File};
+use std::iter;
+use std::path::Path;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{exit, Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use rustc_serialize::json;
 use handlebars::Handlebars;
+use rustc_serialize::json::{Json, ToJson};
+use rust_miscs::command;
+use rust_miscs::env_file;
+use rust_miscs::expand_templates;
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+  Json,
+  Toml,
+  Csv,
+}
+
+#[derive(Debug, Deserialize)]
 struct Args {
   flag_parallel: bool,
+  flag_dry_run: bool,
+  flag_jobs: Option<usize>,
+  flag_timeout: Option<u64>,
+  flag_stdin_field: Option<String>,
+  flag_capture: bool,
+  flag_format: Option<Format>,
+  flag_exit_on_error: bool,
+  flag_env_from_json: bool,
+  flag_env_prefix: Option<String>,
+  flag_null_delimited: bool,
+  flag_delimiter: Option<String>,
+  flag_shell: bool,
+  flag_group_by: Option<String>,
+  flag_env_file: Option<String>,
+  flag_working_dir: Option<String>,
+  flag_retry: u32,
+  flag_retry_delay: u64,
+  flag_input: Option<String>,
+  flag_template_file: Vec<String>,
   arg_name: String,
   arg_args: Vec<String>,
 }
 
-const USAGE: &'static str = r"
+const USAGE: &str = r"
 JSON version of xargs
 
 Usage:
-  jsonargs [--parallel] <name> [<args>...]
+  jsonargs [options] [--template-file=<PATH>]... <name> [<args>...]
   jsonargs (-h | --help)
 
 Options:
-  -h --help     Show this message.
-  --parallel    Run each command parallel
+  -h --help         Show this message.
+  --parallel        Run each command parallel
+  -n --dry-run      Print the commands instead of running them
+  -j --jobs=<N>     Maximum number of commands run at once in parallel mode
+                    (default: the number of logical CPUs)
+  --timeout=<SECS>  Kill each command which does not finish within SECS seconds
+  --stdin-field=<KEY>
+                    Write the value of KEY to the standard input of each command
+  --capture         Capture the output of each command and print it as JSON
+                    along with the input record and the exit code. Only the
+                    last attempt of a retried command is printed
+  --format=<FMT>    The format of input records: json, toml or csv [default: json]
+  -0 --null-delimited
+                    Input records are separated by NUL characters, not newlines
+  --delimiter=<CHAR>
+                    Input records are separated by CHAR, not newlines
+  -I --input=<FILE>
+                    Read input records from FILE instead of stdin ('-' means stdin)
+  --shell           Join the command and the rendered arguments with spaces, and run
+                    it via the shell (sh -c, or cmd /C on Windows)
+  --group-by=<KEY>  Run the command once per group of the records sharing the value of KEY.
+                    The group is written as a JSON array to a temporary file, whose path
+                    is given as {{_group_file}} (and the value of KEY as {{_group_key}}).
+                    With --dry-run, the file is not written and a placeholder is shown
+  --env-file=<PATH>
+                    Read the variables from the .env file at PATH, which are merged
+                    into every record (the fields of the record take precedence)
+  --working-dir=<TEMPLATE>
+                    Run each command in the directory rendered from TEMPLATE. The record
+                    is skipped (as a failure) if the directory does not exist
+  --template-file=<PATH>
+                    Read a template from the file at PATH, which is rendered into the
+                    last argument. Can be given multiple times
+  --retry=<N>       Retry each failed command up to N times [default: 0]
+  --retry-delay=<MS>
+                    Wait MS milliseconds before each retry [default: 0]
+  --exit-on-error   Stop starting new commands after a command fails
+  --env-from-json   Pass the fields of each record to the command as environment
+                    variables, whose names are the uppercased keys
+  --env-prefix=<PREFIX>
+                    The prefix of the names of environment variables set by
+                    --env-from-json
 ";
 
 fn main() {
-  let args = docopt::Docopt::new(USAGE)
-    .and_then(|opt| opt.decode())
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
     .unwrap_or_else(|e| e.exit());
-  let Args { arg_name: name, arg_args: args, flag_parallel: parallel } = args;
 
-  let targs: Vec<Handlebars> = args.into_iter()
-    .map(|arg| -> Result<Handlebars, handlebars::TemplateError> {
-      let mut handlebars = Handlebars::new();
-      handlebars.register_template_string("dummy", arg)?;
-      Ok(handlebars)
-    })
-    .try_collect()
-    .unwrap();
+  let result = match args.flag_input.clone() {
+    Some(ref path) if path != "-" => {
+      let file = File::open(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        exit(1);
+      });
+      run(BufReader::new(file), io::stdout(), args)
+    }
+    _ => {
+      let stdin = io::stdin();
+      let stdin = stdin.lock();
+      run(stdin, io::stdout(), args)
+    }
+  };
+
+  match result {
+    Ok(status) => exit(status.code),
+    Err(message) => {
+      eprintln!("{}", message);
+      exit(1);
+    }
+  }
+}
+
+// The aggregated exit status of the executed commands.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct RunStatus {
+  // The maximum exit code of the commands, or 0 if all of them succeeded.
+  code: i32,
+}
 
+impl RunStatus {
+  fn update(&mut self, code: i32) {
+    if code > self.code {
+      self.code = code;
+    }
+  }
+}
+
+// Return the exit code of a finished command.
+//
+// A command which is killed by a signal or could not be waited is treated as the exit code 1.
+fn exit_code(output: &io::Result<Output>) -> i32 {
+  match *output {
+    Ok(ref output) if output.status.success() => 0,
+    Ok(ref output) => output.status.code().unwrap_or(1),
+    Err(_) => 1,
+  }
+}
+
+// The dry-run command lines and the captured records are written to `out`.
+fn run<R, W>(reader: R, out: W, args: Args) -> Result<RunStatus, String>
+  where R: BufRead,
+        W: Write + Send + 'static
+{
+  let Args { arg_name: name,
+             arg_args: args,
+             flag_parallel: parallel,
+             flag_dry_run: dry_run,
+             flag_jobs: jobs,
+             flag_timeout: timeout,
+             flag_stdin_field: stdin_field,
+             flag_capture: capture,
+             flag_format: format,
+             flag_exit_on_error: exit_on_error,
+             flag_env_from_json: env_from_json,
+             flag_env_prefix: env_prefix,
+             flag_null_delimited: null_delimited,
+             flag_delimiter: delimiter,
+             flag_shell: shell,
+             flag_group_by: group_by,
+             flag_env_file: env_file,
+             flag_working_dir: working_dir,
+             flag_retry: retries,
+             flag_retry_delay: retry_delay,
+             flag_input: _,
+             flag_template_file: template_files } = args;
+  let env_prefix = env_prefix.unwrap_or_default();
+  let format = format.unwrap_or(Format::Json);
+  let jobs = jobs.unwrap_or_else(num_cpus::get);
+  if jobs == 0 {
+    return Err("the number of jobs must be greater than 0.".to_owned());
+  }
+  let timeout = timeout.map(Duration::from_secs);
+  let retry_delay = Duration::from_millis(retry_delay);
+  let delimiter = match delimiter {
+    Some(ref delimiter) if null_delimited => {
+      return Err(format!("--delimiter={} cannot be used with --null-delimited.", delimiter))
+    }
+    Some(delimiter) => {
+      let mut chars = delimiter.chars();
+      match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => return Err("the delimiter must be a single character.".to_owned()),
+      }
+    }
+    None => None,
+  };
+
+  let mut args = args;
+  for path in &template_files {
+    let template = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    args.push(template.trim_end_matches('\n').to_owned());
+  }
+  let targs = command::compile_templates(args).map_err(|e| e.to_string())?;
+  let working_dir = match working_dir {
+    Some(template) => {
+      Some(command::compile_templates(Some(template)).map_err(|e| e.to_string())?)
+    }
+    None => None,
+  };
+
+  // Shared with the threads of `--parallel`, so that their records never interleave.
+  let out = Arc::new(Mutex::new(out));
   let mut childs = Vec::new();
+  let mut status = RunStatus::default();
+  // The error which stopped the loop, returned after the running commands are finished.
+  let mut error = None;
 
-  let stdin = io::stdin();
-  for line in stdin.lock().lines() {
-    let decoded: BTreeMap<String, String> = json::decode(&line.unwrap()).unwrap();
-    let xargs: Vec<String> = targs.iter()
-      .map(|ref targ| targ.render("dummy", &decoded))
-      .try_collect()
-      .unwrap();
+  // Set when a command fails under `--exit-on-error`, so that no more commands are started.
+  let aborted = Arc::new(AtomicBool::new(false));
 
-    let mut child = Command::new(&name)
-      .args(&xargs)
-      .stdout(Stdio::inherit())
-      .stderr(Stdio::inherit())
-      .spawn()
-      .unwrap();
+  // The channel is used as a counting semaphore: it holds one token per free job slot.
+  let (token_tx, token_rx) = mpsc::channel();
+  for _ in 0..jobs {
+    token_tx.send(()).unwrap();
+  }
+
+  let env_vars = match env_file {
+    Some(ref path) => env_file::load(path).map_err(|e| format!("{}: {}", path, e))?,
+    None => BTreeMap::new(),
+  };
+
+  let records = records(reader, format, null_delimited, delimiter);
+  let records = match group_by {
+    Some(ref key) => group_records(records, key, !dry_run)?,
+    None => records,
+  };
+
+  for record in records {
+    let rendered = record.and_then(|mut record| {
+      merge_env_vars(&env_vars, &mut record.data, &mut record.fields);
+      let rendered = render_record(&record,
+                                   &targs,
+                                   working_dir.as_deref(),
+                                   stdin_field.as_deref())?;
+      Ok((record, rendered))
+    });
+    let (record, rendered) = match rendered {
+      Ok(rendered) => rendered,
+      Err(e) => {
+        error = Some(e);
+        break;
+      }
+    };
+    let Record { data, fields: decoded, group_file } = record;
+    let Rendered { args: xargs, working_dir, input } = rendered;
+
+    if dry_run {
+      let mut line = String::new();
+      if let Some(ref dir) = working_dir {
+        line.push_str(&format!("cd {} && ", shell_quote(dir)));
+      }
+      if shell {
+        line.push_str(&shell_line(&name, &xargs));
+      } else {
+        let command: Vec<String> = Some(&name)
+          .into_iter()
+          .chain(&xargs)
+          .map(|arg| shell_quote(arg))
+          .collect();
+        line.push_str(&command.join(" "));
+      }
+      writeln!(out.lock().unwrap(), "{}", line).map_err(|e| e.to_string())?;
+      continue;
+    }
+
+    if let Some(ref dir) = working_dir {
+      if !Path::new(dir).is_dir() {
+        eprintln!("{}: the working directory does not exist: {}", name, dir);
+        status.update(1);
+        if exit_on_error {
+          break;
+        }
+        continue;
+      }
+    }
+
+    if parallel {
+      token_rx.recv().unwrap();
+    }
+    if aborted.load(Ordering::SeqCst) {
+      break;
+    }
+
+    let child_stdout = if capture {
+      Stdio::piped()
+    } else {
+      Stdio::inherit()
+    };
+    let mut command = if shell {
+      let (program, program_args) = shell_command(shell_line(&name, &xargs));
+      command::build_command(program, &program_args, parallel)
+    } else {
+      command::build_command(&name, &xargs, parallel)
+    };
+    command.stdout(child_stdout);
+    // Otherwise, the standard input is the one given by `build_command`.
+    if input.is_some() {
+      command.stdin(Stdio::piped());
+    }
+    if let Some(ref dir) = working_dir {
+      command.current_dir(dir);
+    }
+    if env_from_json {
+      for (key, value) in &decoded {
+        command.env(format!("{}{}", env_prefix, key.to_uppercase()), value);
+      }
+    }
     if !parallel {
-      child.wait().unwrap();
+      let result = run_with_retry(&mut command, retries, retry_delay, |child| {
+        complete(&name, child, input.clone(), timeout)
+      });
+      if capture {
+        print_captured(&out, &data, &result);
+      }
+      let code = exit_code(&result);
+      drop(group_file);
+      status.update(code);
+      if code != 0 && exit_on_error {
+        aborted.store(true, Ordering::SeqCst);
+      }
     } else {
-      childs.push(thread::spawn(move || child.wait().unwrap()));
+      let token_tx = token_tx.clone();
+      let name = name.clone();
+      let aborted = aborted.clone();
+      let out = out.clone();
+      childs.push(thread::spawn(move || {
+        let result = run_with_retry(&mut command, retries, retry_delay, |child| {
+          complete(&name, child, input.clone(), timeout)
+        });
+        if capture {
+          print_captured(&out, &data, &result);
+        }
+        let code = exit_code(&result);
+        drop(group_file);
+        if code != 0 && exit_on_error {
+          aborted.store(true, Ordering::SeqCst);
+        }
+        token_tx.send(()).unwrap();
+        code
+      }));
     }
   }
 
+  // Wait for all of the running commands, even if the execution has been aborted.
   for child in childs {
-    child.join().unwrap();
+    status.update(child.join().unwrap());
+  }
+
+  match error {
+    Some(error) => Err(error),
+    None => Ok(status),
+  }
+}
+
+// The command line rendered from a record.
+struct Rendered {
+  args: Vec<String>,
+  working_dir: Option<String>,
+  // The input of the command given by `--stdin-field`.
+  input: Option<String>,
+}
+
+fn render_record(record: &Record,
+                 templates: &[Handlebars],
+                 working_dir: Option<&[Handlebars]>,
+                 stdin_field: Option<&str>)
+                 -> Result<Rendered, String> {
+  let args = expand_templates(templates, &record.data).map_err(|e| e.to_string())?;
+  let working_dir = match working_dir {
+    Some(template) => {
+      Some(expand_templates(template, &record.data).map_err(|e| e.to_string())?.remove(0))
+    }
+    None => None,
+  };
+  let input = match stdin_field {
+    Some(key) => {
+      Some(record.fields
+        .get(key)
+        .cloned()
+        .ok_or_else(|| format!("the field '{}' is not found.", key))?)
+    }
+    None => None,
+  };
+  Ok(Rendered {
+    args,
+    working_dir,
+    input,
+  })
+}
+
+// Quote the string so that the shell treats it as a single word.
+fn shell_quote(s: &str) -> String {
+  let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-+=.,:/@%".contains(c);
+  if !s.is_empty() && s.chars().all(is_safe) {
+    s.to_owned()
+  } else {
+    format!("'{}'", s.replace("'", r"'\''"))
   }
 }
 
+// Merge the variables from `--env-file` into the record, without overriding its fields.
+fn merge_env_vars(env_vars: &BTreeMap<String, String>,
+                  data: &mut Json,
+                  fields: &mut BTreeMap<String, String>) {
+  for (key, value) in env_vars {
+    fields.entry(key.clone()).or_insert_with(|| value.clone());
+    if let Json::Object(ref mut object) = *data {
+      object.entry(key.clone()).or_insert_with(|| Json::String(value.clone()));
+    }
+  }
+}
 
-trait TryCollect: Iterator {
-  type Unwrapped;
-  type Error;
-  fn try_collect(self) -> Result<Vec<Self::Unwrapped>, Self::Error>;
+// Join the command and its arguments into a command line, without quoting them.
+fn shell_line(name: &str, args: &[String]) -> String {
+  let mut line = name.to_owned();
+  for arg in args {
+    line.push(' ');
+    line.push_str(arg);
+  }
+  line
 }
 
-impl<I, T, E, F> TryCollect for std::iter::Map<I, F>
-  where I: Iterator,
-        F: FnMut(I::Item) -> Result<T, E>
+// The shell and its arguments to run the command line.
+#[cfg(windows)]
+fn shell_command(line: String) -> (&'static str, Vec<String>) {
+  ("cmd", vec!["/C".to_owned(), line])
+}
+
+#[cfg(not(windows))]
+fn shell_command(line: String) -> (&'static str, Vec<String>) {
+  ("sh", vec!["-c".to_owned(), line])
+}
+
+// Run the command, and run it again up to `retries` times while it fails.
+//
+// `complete` waits for each spawned process to finish. Only the result of the last
+// attempt is returned.
+fn run_with_retry<F>(command: &mut Command,
+                     retries: u32,
+                     delay: Duration,
+                     mut complete: F)
+                     -> io::Result<Output>
+  where F: FnMut(Child) -> io::Result<Output>
 {
-  type Unwrapped = T;
-  type Error = E;
+  let mut attempt = 0;
+  loop {
+    let result = match command.spawn() {
+      Ok(child) => complete(child),
+      Err(e) => {
+        eprintln!("{:?}: {}", command, e);
+        Err(e)
+      }
+    };
+    if exit_code(&result) == 0 || attempt >= retries {
+      return result;
+    }
+
+    attempt += 1;
+    eprintln!("[retry {}/{}] {:?}", attempt, retries, command);
+    thread::sleep(delay);
+  }
+}
+
+// Wait for the command to finish and report its error.
+fn complete(name: &str,
+            child: Child,
+            input: Option<String>,
+            timeout: Option<Duration>)
+            -> io::Result<Output> {
+  finish_child(child, input, timeout).map_err(|e| {
+    eprintln!("{}: {}", name, e);
+    e
+  })
+}
+
+// Write the captured output of the command as a JSON object.
+//
+// Nothing is written when the command could not be run, which has been reported already.
+fn print_captured<W: Write>(out: &Mutex<W>, data: &Json, output: &io::Result<Output>) {
+  let output = match *output {
+    Ok(ref output) => output,
+    Err(_) => return,
+  };
+  let mut record = BTreeMap::new();
+  record.insert("input".to_owned(), data.clone());
+  record.insert("stdout".to_owned(),
+                Json::String(String::from_utf8_lossy(&output.stdout).into_owned()));
+  record.insert("exit".to_owned(),
+                output.status.code().map_or(Json::Null, |code| Json::I64(code as i64)));
+
+  if let Err(e) = writeln!(out.lock().unwrap(), "{}", Json::Object(record)) {
+    eprintln!("{}", e);
+  }
+}
+
+// Feed the input to the child process (if any) and wait for it to exit.
+//
+// The standard output of the child is collected into the returned `Output`
+// when it is piped.
+fn finish_child(mut child: Child,
+                input: Option<String>,
+                timeout: Option<Duration>)
+                -> io::Result<Output> {
+  // Read the output in the background so that the child never blocks on a full pipe.
+  let reader = child.stdout.take().map(|mut stdout| {
+    thread::spawn(move || -> io::Result<Vec<u8>> {
+      let mut buf = Vec::new();
+      stdout.read_to_end(&mut buf)?;
+      Ok(buf)
+    })
+  });
+
+  // The input is written in the background as well, so that a child which never reads it
+  // cannot block the timeout.
+  let writer = input.map(|input| {
+    let mut stdin = child.stdin.take().unwrap();
+    thread::spawn(move || -> io::Result<()> {
+      // The pipe is closed when `stdin` is dropped, so the child receives EOF.
+      match stdin.write_all(input.as_bytes()) {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+      }
+    })
+  });
+  let status = wait_child(&mut child, timeout)?;
+  if let Some(writer) = writer {
+    writer.join().unwrap()?;
+  }
+
+  let stdout = match reader {
+    Some(reader) => reader.join().unwrap()?,
+    None => Vec::new(),
+  };
+
+  Ok(Output {
+    status,
+    stdout,
+    stderr: Vec::new(),
+  })
+}
+
+// Wait for the child process to exit, killing it if the timeout elapses first.
+fn wait_child(child: &mut Child, timeout: Option<Duration>) -> io::Result<ExitStatus> {
+  let timeout = match timeout {
+    Some(timeout) => timeout,
+    None => return child.wait(),
+  };
+
+  let start = Instant::now();
+  loop {
+    if let Some(status) = child.try_wait()? {
+      return Ok(status);
+    }
+    if start.elapsed() >= timeout {
+      child.kill()?;
+      child.wait()?;
+      return Err(io::Error::new(io::ErrorKind::TimedOut, "command timed out"));
+    }
+    thread::sleep(Duration::from_millis(10));
+  }
+}
+
+// An input record.
+struct Record {
+  // The data which the templates are rendered with.
+  data: Json,
+  // The flattened fields of the record.
+  fields: BTreeMap<String, String>,
+  // The temporary file of the grouped records, which is removed when dropped.
+  group_file: Option<tempfile::TempPath>,
+}
+
+// Read the input records in the given format.
+//
+// If `null_delimited` is set, the records are separated by NUL characters instead of newlines.
+// Similarly, they are separated by `delimiter` if it is given.
+fn records<'a, R: BufRead + 'a>(reader: R,
+                                format: Format,
+                                null_delimited: bool,
+                                delimiter: Option<char>)
+                                -> Box<dyn Iterator<Item = Result<Record, String>> + 'a> {
+  let decode: fn(&str) -> Result<Record, String> = match format {
+    Format::Json => json_record,
+    Format::Toml => toml_record,
+    Format::Csv => {
+      // The first record is the header, which gives the names of the fields.
+      let mut builder = csv::ReaderBuilder::new();
+      if null_delimited {
+        builder.terminator(csv::Terminator::Any(b'\0'));
+      }
+      if let Some(delimiter) = delimiter {
+        if !delimiter.is_ascii() {
+          let message = "the delimiter of CSV records must be an ASCII character".to_owned();
+          return Box::new(iter::once(Err(message)));
+        }
+        builder.terminator(csv::Terminator::Any(delimiter as u8));
+      }
+      let mut reader = builder.from_reader(reader);
+      let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => return Box::new(iter::once(Err(e.to_string()))),
+      };
+      return Box::new(reader.into_records().map(move |row| {
+        let row = row.map_err(|e| e.to_string())?;
+        let fields: BTreeMap<String, String> = headers.iter()
+          .zip(row.iter())
+          .map(|(key, value)| (key.to_owned(), value.to_owned()))
+          .collect();
+        Ok(Record {
+          data: fields.to_json(),
+          fields,
+          group_file: None,
+        })
+      }));
+    }
+  };
+
+  let lines: Box<dyn Iterator<Item = io::Result<String>> + 'a> = if null_delimited {
+    Box::new(NullDelimitedLines::new(reader))
+  } else if let Some(delimiter) = delimiter {
+    split_records(reader, delimiter)
+  } else {
+    Box::new(reader.lines())
+  };
+  Box::new(lines.map(move |line| decode(&line.map_err(|e| e.to_string())?)))
+}
+
+fn json_record(line: &str) -> Result<Record, String> {
+  let data = decode_record(line)?;
+  Ok(Record {
+    fields: flatten_json(data.clone()),
+    data,
+    group_file: None,
+  })
+}
+
+fn toml_record(line: &str) -> Result<Record, String> {
+  let fields = decode_toml_record(line)?;
+  Ok(Record {
+    data: fields.to_json(),
+    fields,
+    group_file: None,
+  })
+}
+
+// Group the records by the value of the field `key`, buffering all of them.
+//
+// The groups are ordered by the value, and the records in each group keep the input order.
+// The temporary files of the groups are written only if `write_files` is set.
+fn group_records<'a>(records: Box<dyn Iterator<Item = Result<Record, String>> + 'a>,
+                     key: &str,
+                     write_files: bool)
+                     -> Result<Box<dyn Iterator<Item = Result<Record, String>> + 'a>, String> {
+  let mut records: Vec<Record> = records.collect::<Result<_, _>>()?;
+  if records.iter().any(|record| !record.fields.contains_key(key)) {
+    return Err(format!("the field '{}' is not found.", key));
+  }
+  records.sort_by(|a, b| a.fields[key].cmp(&b.fields[key]));
+
+  let mut groups: Vec<(String, Vec<Json>)> = Vec::new();
+  for record in records {
+    let value = record.fields[key].clone();
+    if let Some(&mut (ref last, ref mut items)) = groups.last_mut() {
+      if *last == value {
+        items.push(record.data);
+        continue;
+      }
+    }
+    groups.push((value, vec![record.data]));
+  }
+
+  Ok(Box::new(groups.into_iter().map(move |(value, items)| if write_files {
+    group_record(value, items)
+  } else {
+    Ok(group_placeholder(value))
+  })))
+}
+
+// Write a group of records into a temporary file, and make the record which refers to it.
+fn group_record(value: String, items: Vec<Json>) -> Result<Record, String> {
+  let mut file = tempfile::NamedTempFile::new().map_err(|e| e.to_string())?;
+  write!(file, "{}", Json::Array(items)).map_err(|e| e.to_string())?;
+  let path = file.into_temp_path();
+
+  let mut record = group_placeholder(value);
+  record.fields.insert("_group_file".to_owned(), path.to_string_lossy().into_owned());
+  record.data = record.fields.to_json();
+  record.group_file = Some(path);
+  Ok(record)
+}
 
-  fn try_collect(self) -> Result<Vec<T>, E> {
+// The value of `_group_file` shown by `--dry-run`.
+const GROUP_FILE_PLACEHOLDER: &str = "GROUP_FILE";
+
+// The record of a group whose file is not written, for `--dry-run`.
+//
+// The file would be removed before the printed command is run, so it is not written at all.
+fn group_placeholder(value: String) -> Record {
+  let mut fields = BTreeMap::new();
+  fields.insert("_group_file".to_owned(), GROUP_FILE_PLACEHOLDER.to_owned());
+  fields.insert("_group_key".to_owned(), value);
+  Record {
+    data: fields.to_json(),
+    fields,
+    group_file: None,
+  }
+}
+
+
+// Read the whole input and split it into the records separated by `delimiter`.
+fn split_records<R: Read>(mut reader: R,
+                          delimiter: char)
+                          -> Box<dyn Iterator<Item = io::Result<String>>> {
+  let mut input = String::new();
+  if let Err(e) = reader.read_to_string(&mut input) {
+    return Box::new(iter::once(Err(e)));
+  }
+
+  let mut records: Vec<io::Result<String>> =
+    input.split(delimiter).map(|record| Ok(record.to_owned())).collect();
+  // The delimiter after the last record (possibly followed by a newline) does not start
+  // a new record.
+  if let Some(Ok(last)) = records.last() {
+    if last.trim().is_empty() {
+      records.pop();
+    }
+  }
+  Box::new(records.into_iter())
+}
+
+// An iterator over the NUL-separated chunks of the reader, analogous to `io::Lines`.
+struct NullDelimitedLines<R: BufRead> {
+  reader: R,
+}
+
+impl<R: BufRead> NullDelimitedLines<R> {
+  fn new(reader: R) -> NullDelimitedLines<R> {
+    NullDelimitedLines { reader }
+  }
+}
+
+impl<R: BufRead> Iterator for NullDelimitedLines<R> {
+  type Item = io::Result<String>;
+
+  fn next(&mut self) -> Option<io::Result<String>> {
+    // `read_until` keeps reading across the boundaries of the internal buffer.
     let mut buf = Vec::new();
-    for elem in self {
-      buf.push(elem?);
+    match self.reader.read_until(b'\0', &mut buf) {
+      Ok(0) => None,
+      Ok(_) => {
+        if buf.last() == Some(&b'\0') {
+          buf.pop();
+        }
+        Some(String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+      }
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
+
+// Decode an inline table of TOML (e.g. `{name = "Alice", age = 30}`) into the template variables.
+fn decode_toml_record(line: &str) -> Result<BTreeMap<String, String>, String> {
+  let document = format!("record = {}", line);
+  let mut table: toml::value::Table = toml::from_str(&document).map_err(|e| e.to_string())?;
+  match table.remove("record") {
+    Some(toml::Value::Table(record)) => {
+      Ok(record.into_iter()
+        .map(|(key, value)| {
+          let value = match value {
+            toml::Value::String(s) => s,
+            value => value.to_string(),
+          };
+          (key, value)
+        })
+        .collect())
+    }
+    _ => Err(format!("expected an inline table: {}", line)),
+  }
+}
+
+// Decode an input line, which is a JSON object or array.
+fn decode_record(line: &str) -> Result<Json, String> {
+  match Json::from_str(line) {
+    Ok(data @ Json::Object(_)) |
+    Ok(data @ Json::Array(_)) => Ok(data),
+    Ok(_) => Err(format!("expected an object or an array: {}", line)),
+    Err(e) => Err(format!("failed to parse input as JSON: {}", e)),
+  }
+}
+
+// Flatten a JSON value into the map of template variables.
+//
+// The fields of nested objects are bound to the dot-separated keys (e.g. "user.name"),
+// and the elements of arrays are bound to their indices (e.g. "0", "list.1").
+fn flatten_json(value: Json) -> BTreeMap<String, String> {
+  fn flatten_into(key: Option<String>, value: Json, fields: &mut BTreeMap<String, String>) {
+    let child_key = |k: String| match key {
+      Some(ref key) => format!("{}.{}", key, k),
+      None => k,
+    };
+    match value {
+      Json::Object(object) => {
+        for (k, v) in object {
+          flatten_into(Some(child_key(k)), v, fields);
+        }
+      }
+      Json::Array(items) => {
+        for (i, item) in items.into_iter().enumerate() {
+          flatten_into(Some(child_key(i.to_string())), item, fields);
+        }
+      }
+      Json::String(s) => {
+        fields.insert(key.unwrap_or_default(), s);
+      }
+      value => {
+        fields.insert(key.unwrap_or_default(), value.to_string());
+      }
+    }
+  }
+
+  let mut fields = BTreeMap::new();
+  flatten_into(None, value, &mut fields);
+  fields
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  // Parse the command line arguments (without the program name) as `main` does.
+  fn parse_args(argv: &[&str]) -> Args {
+    docopt::Docopt::new(USAGE)
+      .and_then(|opt| opt.argv(iter::once("jsonargs").chain(argv.iter().cloned())).deserialize())
+      .unwrap()
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn jobs_limit_the_number_of_running_commands() {
+    let dir = tempfile::tempdir().unwrap();
+    let running = dir.path().join("running");
+    fs::create_dir(&running).unwrap();
+    let log = dir.path().join("log");
+
+    // Each command records the number of the commands running at once, including itself.
+    let script = dir.path().join("script.sh");
+    fs::write(&script,
+              format!("touch {0}/$1\nls {0} | wc -l >> {1}\nsleep 0.02\nrm {0}/$1\n",
+                      running.display(),
+                      log.display()))
+      .unwrap();
+
+    let input: String = (0..200).map(|i| format!("{{\"i\":{}}}\n", i)).collect();
+    let args = parse_args(&["--parallel", "-j", "4", "sh", script.to_str().unwrap(), "{{i}}"]);
+    let status = run(Cursor::new(input), io::sink(), args).unwrap();
+    assert_eq!(status, RunStatus { code: 0 });
+
+    let counts: Vec<usize> = fs::read_to_string(&log)
+      .unwrap()
+      .lines()
+      .map(|line| line.trim().parse().unwrap())
+      .collect();
+    assert_eq!(counts.len(), 200);
+    assert!(counts.iter().all(|&count| (1..=4).contains(&count)), "{:?}", counts);
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn timeout_kills_the_command() {
+    let args = parse_args(&["--timeout", "1", "sleep", "60"]);
+    let start = Instant::now();
+    let status = run(Cursor::new("{}\n"), io::sink(), args).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_ne!(status.code, 0);
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn finish_child_writes_the_input() {
+    let child = Command::new("cat").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    let output = finish_child(child, Some("hello".to_owned()), None).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"hello");
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn timeout_applies_to_the_command_not_reading_input() {
+    let child = Command::new("sleep").arg("60").stdin(Stdio::piped()).spawn().unwrap();
+    // Larger than the pipe buffer, so that writing it blocks.
+    let input = "x".repeat(4 * 1024 * 1024);
+    let start = Instant::now();
+    let result = finish_child(child, Some(input), Some(Duration::from_secs(1)));
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn malformed_record_is_an_error() {
+    let args = parse_args(&["echo", "{{a}}"]);
+    assert!(run(Cursor::new("{\"a\": 1\n"), io::sink(), args).is_err());
+  }
+
+  #[test]
+  fn missing_stdin_field_is_an_error() {
+    let args = parse_args(&["--stdin-field", "text", "cat"]);
+    assert!(run(Cursor::new("{\"a\": 1}\n"), io::sink(), args).is_err());
+  }
+
+  #[test]
+  fn dry_run_does_not_write_group_files() {
+    let records: Vec<Result<Record, String>> = vec![json_record(r#"{"k": "a"}"#)];
+    let groups: Vec<Record> = group_records(Box::new(records.into_iter()), "k", false)
+      .unwrap()
+      .collect::<Result<_, _>>()
+      .unwrap();
+    assert_eq!(groups.len(), 1);
+    assert!(groups[0].group_file.is_none());
+    assert_eq!(groups[0].fields["_group_file"], GROUP_FILE_PLACEHOLDER);
+    assert_eq!(groups[0].fields["_group_key"], "a");
+  }
+
+  #[test]
+  fn env_vars_do_not_override_the_fields() {
+    let env_vars = env_file::parse("A=env\nB=env\n").unwrap();
+    let Record { mut data, mut fields, .. } = json_record(r#"{"A": "json"}"#).unwrap();
+    merge_env_vars(&env_vars, &mut data, &mut fields);
+    assert_eq!(fields["A"], "json");
+    assert_eq!(fields["B"], "env");
+    assert_eq!(data.find("A"), Some(&Json::String("json".to_owned())));
+    assert_eq!(data.find("B"), Some(&Json::String("env".to_owned())));
+  }
+
+  // A writer whose content can be read after it is moved into `run`.
+  #[derive(Clone, Default)]
+  struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn capture_prints_only_the_last_attempt() {
+    let out = SharedBuf::default();
+    let args = parse_args(&["--capture", "--retry", "2", "false"]);
+    let status = run(Cursor::new("{\"i\": 1}\n{\"i\": 2}\n"), out.clone(), args).unwrap();
+    assert_eq!(status.code, 1);
+
+    let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let records: Vec<Json> = out.lines().map(|line| Json::from_str(line).unwrap()).collect();
+    assert_eq!(records.len(), 2, "{}", out);
+    for record in &records {
+      assert_eq!(record["exit"].as_i64(), Some(1));
     }
-    Ok(buf)
   }
 }
@@ -0,0 +1,196 @@
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate linked_hash_map;
+#[macro_use]
+extern crate rust_miscs;
+#[cfg(test)]
+extern crate tempfile;
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::process::exit;
+
+use linked_hash_map::LinkedHashMap;
+use rustc_serialize::json::{self, Json};
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_key: String,
+  flag_max_files: usize,
+  arg_output_dir: String,
+}
+
+const USAGE: &str = r"
+Split newline-delimited JSON records into files by the value of a field
+
+Usage:
+  jsplit [options] --key=<FIELD> <output-dir>
+  jsplit (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --key=<FIELD>     Write each record to <output-dir>/<value of FIELD>.ndjson
+  --max-files=<N>   Maximum number of files kept open at once [default: 1000]
+";
+
+def_error! {
+  JsplitError {
+    Io(io::Error)           => "I/O error: {0}",
+    Json(json::ParserError) => "invalid JSON: {0}",
+    Other(String)           => "{0}",
+  }
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  let stdin = io::stdin();
+  if let Err(e) = run(stdin.lock(), args) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
+
+fn run<R: BufRead>(reader: R, args: Args) -> Result<(), JsplitError> {
+  if args.flag_max_files == 0 {
+    return Err("the number of files must be greater than 0.".to_owned().into());
+  }
+  let output_dir = Path::new(&args.arg_output_dir);
+  fs::create_dir_all(output_dir)?;
+
+  let mut outputs = Outputs::new(output_dir, args.flag_max_files);
+  for (i, line) in reader.lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let record = Json::from_str(&line)?;
+    let value = match record.find(&args.flag_key) {
+      Some(Json::String(s)) => s.clone(),
+      Some(value) => value.to_string(),
+      None => {
+        return Err(format!("line {}: the field '{}' is not found", i + 1, args.flag_key).into())
+      }
+    };
+    writeln!(outputs.get(&value)?, "{}", line)?;
+  }
+
+  outputs.flush()?;
+  Ok(())
+}
+
+// The output files, of which at most `max_files` are kept open.
+//
+// The least recently used file is closed when the limit is reached, and reopened
+// (in append mode) when it is written again.
+struct Outputs<'a> {
+  dir: &'a Path,
+  max_files: usize,
+  // The files are keyed by their names, so that the values which are mapped to the same file
+  // (e.g. "a/b" and "a_b") share a writer.
+  files: LinkedHashMap<String, BufWriter<File>>,
+  // The names of the files which have been created, so that they are not truncated on reopening.
+  created: HashSet<String>,
+}
+
+impl<'a> Outputs<'a> {
+  fn new(dir: &'a Path, max_files: usize) -> Outputs<'a> {
+    Outputs {
+      dir,
+      max_files,
+      files: LinkedHashMap::new(),
+      created: HashSet::new(),
+    }
+  }
+
+  fn get(&mut self, value: &str) -> io::Result<&mut BufWriter<File>> {
+    let name = file_name(value);
+    if self.files.get_refresh(&name).is_none() {
+      if self.files.len() >= self.max_files {
+        if let Some((_, mut file)) = self.files.pop_front() {
+          file.flush()?;
+        }
+      }
+      let path = self.dir.join(&name);
+      let file = if self.created.insert(name.clone()) {
+        File::create(path)?
+      } else {
+        OpenOptions::new().append(true).open(path)?
+      };
+      self.files.insert(name.clone(), BufWriter::new(file));
+    }
+    Ok(self.files.get_refresh(&name).unwrap())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    for (_, file) in self.files.iter_mut() {
+      file.flush()?;
+    }
+    Ok(())
+  }
+}
+
+// The name of the file for the value, where the characters not allowed in file names
+// are replaced with `_`.
+fn file_name(value: &str) -> String {
+  let mut name: String = value.chars()
+    .map(|c| match c {
+      '/' | '\\' | '\0' => '_',
+      c => c,
+    })
+    .collect();
+  if name.is_empty() || name == "." || name == ".." {
+    name = format!("_{}", name);
+  }
+  format!("{}.ndjson", name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn split(input: &str, dir: &Path, max_files: usize) {
+    let args = Args {
+      flag_key: "group".to_owned(),
+      flag_max_files: max_files,
+      arg_output_dir: dir.to_str().unwrap().to_owned(),
+    };
+    run(input.as_bytes(), args).unwrap();
+  }
+
+  #[test]
+  fn records_are_split_into_the_groups() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut input = String::new();
+    for i in 0..100 {
+      input.push_str(&format!("{{\"group\":\"g{}\",\"id\":\"{:02}\"}}\n", i % 5, i));
+    }
+    // Fewer open files than groups, so that the files are reopened in append mode.
+    split(&input, dir.path(), 2);
+
+    let line_len = r#"{"group":"g0","id":"00"}"#.len() + 1;
+    for g in 0..5 {
+      let path = dir.path().join(format!("g{}.ndjson", g));
+      assert_eq!(fs::metadata(&path).unwrap().len() as usize, 20 * line_len);
+      let content = fs::read_to_string(&path).unwrap();
+      assert!(content.lines().all(|line| line.contains(&format!("\"g{}\"", g))));
+    }
+  }
+
+  #[test]
+  fn colliding_values_share_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    split("{\"group\":\"a/b\"}\n{\"group\":\"a_b\"}\n{\"group\":\"a/b\"}\n",
+          dir.path(),
+          1);
+    assert_eq!(fs::read_to_string(dir.path().join("a_b.ndjson")).unwrap(),
+               "{\"group\":\"a/b\"}\n{\"group\":\"a_b\"}\n{\"group\":\"a/b\"}\n");
+  }
+}
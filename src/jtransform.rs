@@ -0,0 +1,191 @@
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate handlebars;
+#[macro_use]
+extern crate rust_miscs;
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+
+use handlebars::{Handlebars, Helper, RenderContext, RenderError, TemplateError};
+use rustc_serialize::json::{self, Json};
+use rust_miscs::command;
+use rust_miscs::expand_templates;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_map: String,
+  flag_filter: Option<String>,
+  flag_json_output: bool,
+}
+
+const USAGE: &str = r#"
+Transform newline-delimited JSON records with a template
+
+Usage:
+  jtransform [options] --map=<TEMPLATE>
+  jtransform (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --map=<TEMPLATE>  The Handlebars template rendered with each record. Available are
+                    the helpers upper, lower, trim and default, e.g.
+                    {{upper name}} or {{default name "unknown"}}
+  --filter=<KEY=VALUE>
+                    Skip the records whose field KEY is not VALUE
+  --json-output     Print each result as {"result": ...}
+"#;
+
+def_error! {
+  JtransformError {
+    Io(io::Error)             => "I/O error: {0}",
+    Json(json::ParserError)   => "invalid JSON: {0}",
+    Template(TemplateError)   => "invalid template: {0}",
+    Render(RenderError)       => "failed to render: {0}",
+    Other(String)             => "{0}",
+  }
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  let stdin = io::stdin();
+  let stdout = io::stdout();
+  if let Err(e) = run(stdin.lock(), stdout.lock(), args) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
+
+#[allow(clippy::result_large_err)]
+fn run<R: BufRead, W: Write>(reader: R, mut out: W, args: Args) -> Result<(), JtransformError> {
+  let mut templates = command::compile_templates(Some(&args.flag_map))?;
+  for template in &mut templates {
+    // The output is plain text, not HTML.
+    template.register_escape_fn(handlebars::no_escape);
+    register_helpers(template);
+  }
+
+  let filter = match args.flag_filter {
+    Some(ref filter) => {
+      let mut kv = filter.splitn(2, '=');
+      match (kv.next(), kv.next()) {
+        (Some(key), Some(value)) => Some((key, value)),
+        _ => return Err(format!("the filter must be KEY=VALUE: {}", filter).into()),
+      }
+    }
+    None => None,
+  };
+
+  for line in reader.lines() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let record = Json::from_str(&line)?;
+    if let Some((key, value)) = filter {
+      if record.find(key).map(json_to_string) != Some(value.to_owned()) {
+        continue;
+      }
+    }
+
+    let result = expand_templates(&templates, &record)?.remove(0);
+    if args.flag_json_output {
+      let mut object = BTreeMap::new();
+      object.insert("result".to_owned(), Json::String(result));
+      writeln!(out, "{}", Json::Object(object))?;
+    } else {
+      writeln!(out, "{}", result)?;
+    }
+  }
+
+  out.flush()?;
+  Ok(())
+}
+
+fn register_helpers(handlebars: &mut Handlebars) {
+  handlebars.register_helper("upper", Box::new(upper_helper));
+  handlebars.register_helper("lower", Box::new(lower_helper));
+  handlebars.register_helper("trim", Box::new(trim_helper));
+  handlebars.register_helper("default", Box::new(default_helper));
+}
+
+fn upper_helper(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+  let value = param_string(h, 0)?;
+  rc.writer.write_all(value.to_uppercase().as_bytes())?;
+  Ok(())
+}
+
+fn lower_helper(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+  let value = param_string(h, 0)?;
+  rc.writer.write_all(value.to_lowercase().as_bytes())?;
+  Ok(())
+}
+
+fn trim_helper(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+  let value = param_string(h, 0)?;
+  rc.writer.write_all(value.trim().as_bytes())?;
+  Ok(())
+}
+
+// `{{default field "fallback"}}` renders the fallback if the field is missing, null or empty.
+fn default_helper(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+  let value = param_string(h, 0)?;
+  let value = if value.is_empty() {
+    param_string(h, 1)?
+  } else {
+    value
+  };
+  rc.writer.write_all(value.as_bytes())?;
+  Ok(())
+}
+
+// The parameter of the helper as a string. A missing field is rendered as an empty string.
+fn param_string(h: &Helper, index: usize) -> Result<String, RenderError> {
+  let param = h.param(index)
+    .ok_or_else(|| RenderError::new(format!("{}: missing parameter #{}", h.name(), index)))?;
+  Ok(json_to_string(param.value()))
+}
+
+// Render the value as the templates do: strings without quotes, and null as empty.
+fn json_to_string(value: &Json) -> String {
+  match *value {
+    Json::String(ref s) => s.clone(),
+    Json::Null => String::new(),
+    ref value => value.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn transform(map: &str, input: &str) -> String {
+    let args = Args {
+      flag_map: map.to_owned(),
+      flag_filter: None,
+      flag_json_output: false,
+    };
+    let mut out = Vec::new();
+    run(Cursor::new(input), &mut out, args).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn values_are_not_html_escaped() {
+    assert_eq!(transform("{{a}}", "{\"a\": \"x&y <z>\"}\n"), "x&y <z>\n");
+  }
+
+  #[test]
+  fn helpers_are_registered() {
+    assert_eq!(transform("{{upper a}}-{{default b \"none\"}}", "{\"a\": \"x\"}\n"),
+               "X-none\n");
+  }
+}
@@ -0,0 +1,374 @@
+extern crate regex;
+extern crate glob;
+extern crate handlebars;
+extern crate rustc_serialize;
+extern crate crossbeam;
+extern crate rayon;
+extern crate memmap2;
+extern crate sha2;
+extern crate tempfile;
+#[macro_use]
+extern crate log;
+
+pub mod command;
+pub mod config;
+pub mod env_file;
+pub mod gitignore;
+pub mod index;
+pub mod pattern;
+pub mod traversal;
+
+pub use command::{expand_templates, run_command};
+
+use std::error::Error;
+use std::fmt;
+
+/// An error annotated with a message describing what was being done (e.g. the path).
+#[derive(Debug)]
+pub struct Contexted<E> {
+  pub error: E,
+  pub context: String,
+}
+
+impl<E: fmt::Display> fmt::Display for Contexted<E> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}: {}", self.context, self.error)
+  }
+}
+
+impl<E: Error + 'static> Error for Contexted<E> {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    Some(&self.error)
+  }
+}
+
+/// An extension of `Result` to annotate the error with a context, e.g.
+/// `fs::read_dir(path).with_context(|| path.display().to_string())?`.
+pub trait ExtContext<T, E> {
+  fn context<C: Into<String>>(self, context: C) -> Result<T, Contexted<E>>;
+
+  /// Same as `context`, but the message is built only when the result is an error.
+  fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T, Contexted<E>>;
+}
+
+impl<T, E> ExtContext<T, E> for Result<T, E> {
+  fn context<C: Into<String>>(self, context: C) -> Result<T, Contexted<E>> {
+    self.map_err(|error| {
+      Contexted {
+        error,
+        context: context.into(),
+      }
+    })
+  }
+
+  fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T, Contexted<E>> {
+    self.map_err(|error| {
+      Contexted {
+        error,
+        context: f(),
+      }
+    })
+  }
+}
+
+/// Implement `From<$src>` for the error type `$t`, wrapping the value into the variant `$dst`.
+#[macro_export]
+macro_rules! def_from {
+  ($t:ident, $src:ty => $dst:ident) => {
+    impl From<$src> for $t {
+      fn from(err: $src) -> $t {
+        $t::$dst(err)
+      }
+    }
+  }
+}
+
+/// Same as `def_from!`, but the value is converted into `$via` before wrapped, e.g.
+///
+/// ```ignore
+/// def_from_into!(MyError, &'static str => Msg, String);
+/// let err = MyError::from("oops");  // MyError::Msg("oops".to_owned())
+/// ```
+#[macro_export]
+macro_rules! def_from_into {
+  ($t:ident, $src:ty => $dst:ident, $via:ty) => {
+    impl From<$src> for $t {
+      fn from(err: $src) -> $t {
+        $t::$dst(::std::convert::Into::<$via>::into(err))
+      }
+    }
+  }
+}
+
+/// Implement `TryFrom<$src>` for `$t`, the fallible counterpart of `def_from!`.
+///
+/// The values are mapped to the variants of `$t` by the listed patterns, and any other value
+/// is rejected with `$err::InvalidValue`:
+///
+/// ```ignore
+/// def_try_from! { Color, u8 => ColorError;
+///   0 => Red,
+///   1 => Green,
+/// }
+/// ```
+///
+/// The conversion can also be given as a closure returning `Result<$t, $err>`:
+/// `def_try_from!(Color, u8 => ColorError, |value| ...)`.
+#[macro_export]
+macro_rules! def_try_from {
+  ($t:ident, $src:ty => $err:ident; $($value:pat => $dst:ident),* $(,)*) => {
+    impl ::std::convert::TryFrom<$src> for $t {
+      type Error = $err;
+
+      fn try_from(value: $src) -> Result<$t, $err> {
+        match value {
+          $( $value => Ok($t::$dst), )*
+          _ => Err($err::InvalidValue),
+        }
+      }
+    }
+  };
+
+  ($t:ident, $src:ty => $err:ident, $conv:expr) => {
+    impl ::std::convert::TryFrom<$src> for $t {
+      type Error = $err;
+
+      fn try_from(value: $src) -> Result<$t, $err> {
+        ($conv)(value)
+      }
+    }
+  };
+}
+
+/// Multiple-pairs form of `def_from!`, e.g.
+/// `def_from_all!(MyError; io::Error => Io, regex::Error => Regex)`.
+#[macro_export]
+macro_rules! def_from_all {
+  ($t:ident;) => {};
+
+  ($t:ident; $src:ty => $dst:ident) => {
+    def_from!($t, $src => $dst);
+  };
+
+  ($t:ident; $src:ty => $dst:ident, $($rest:tt)*) => {
+    def_from!($t, $src => $dst);
+    def_from_all!($t; $($rest)*);
+  };
+}
+
+/// Same as `def_from!`, but also implements `Display` and `Error` for the error type.
+///
+/// All variants of the error type must be listed in one invocation, since the `Display`
+/// implementation has to cover all of them. The format string of each variant receives
+/// the wrapped value, and defaults to `"{:?}"` when omitted:
+///
+/// ```ignore
+/// def_from_display! { FilesError;
+///   io::Error => IO, "I/O error: {}";
+///   String    => Other;
+/// }
+/// ```
+#[macro_export]
+macro_rules! def_from_display {
+  (@arms $t:ident [$($dst:ident, $fmt:expr;)*]) => {
+    impl ::std::fmt::Display for $t {
+      fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+          $( $t::$dst(ref err) => write!(f, $fmt, err), )*
+        }
+      }
+    }
+
+    impl ::std::error::Error for $t {}
+  };
+
+  (@arms $t:ident [$($arms:tt)*] $src:ty => $dst:ident, $fmt:expr; $($rest:tt)*) => {
+    def_from!($t, $src => $dst);
+    def_from_display!(@arms $t [$($arms)* $dst, $fmt;] $($rest)*);
+  };
+
+  (@arms $t:ident [$($arms:tt)*] $src:ty => $dst:ident; $($rest:tt)*) => {
+    def_from!($t, $src => $dst);
+    def_from_display!(@arms $t [$($arms)* $dst, "{:?}";] $($rest)*);
+  };
+
+  ($t:ident; $($rest:tt)*) => {
+    def_from_display!(@arms $t [] $($rest)*);
+  };
+}
+
+/// Define an error type, along with the `From` impls of the wrapped values, `Display` and
+/// `Error`, in one declaration:
+///
+/// ```ignore
+/// def_error! {
+///   pub MyError {
+///     Io(io::Error) => "I/O error: {0}",
+///     Parse(String) => "parse error: {0}",
+///   }
+/// }
+/// ```
+///
+/// The format string of each variant receives the wrapped value. `Error::source()` returns
+/// the wrapped value if it is an error itself (`io::Error` above), and `None` otherwise.
+#[macro_export]
+macro_rules! def_error {
+  ($(#[$attr:meta])* $vis:vis $t:ident { $($dst:ident($src:ty) => $fmt:expr),* $(,)* }) => {
+    $(#[$attr])*
+    #[derive(Debug)]
+    $vis enum $t {
+      $( $dst($src), )*
+    }
+
+    $( def_from!($t, $src => $dst); )*
+
+    impl ::std::fmt::Display for $t {
+      fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+          $( $t::$dst(ref err) => write!(f, $fmt, err), )*
+        }
+      }
+    }
+
+    impl ::std::error::Error for $t {
+      fn source(&self) -> Option<&(dyn (::std::error::Error) + 'static)> {
+        #[allow(unused_imports)]
+        use $crate::__private::{ErrorSource, NoErrorSource};
+        match *self {
+          $( $t::$dst(ref err) => (&$crate::__private::Source(err)).error_source(), )*
+        }
+      }
+    }
+  };
+}
+
+// The helpers of `def_error!`, which must be public to be used from the expanded code.
+//
+// `(&Source(value)).error_source()` resolves to `ErrorSource` if the value is an error,
+// and falls back to `NoErrorSource` (which requires one more auto-ref) otherwise.
+#[doc(hidden)]
+pub mod __private {
+  use std::error::Error;
+
+  pub struct Source<'a, T: 'a>(pub &'a T);
+
+  pub trait ErrorSource<'a> {
+    fn error_source(&self) -> Option<&'a (dyn Error + 'static)>;
+  }
+
+  impl<'a, T: Error + 'static> ErrorSource<'a> for Source<'a, T> {
+    fn error_source(&self) -> Option<&'a (dyn Error + 'static)> {
+      Some(self.0)
+    }
+  }
+
+  pub trait NoErrorSource<'a> {
+    fn error_source(&self) -> Option<&'a (dyn Error + 'static)> {
+      None
+    }
+  }
+
+  impl<'a, T> NoErrorSource<'a> for &Source<'a, T> {}
+}
+
+// Declared after the macros, which are used in the module.
+pub mod rmate;
+
+#[cfg(test)]
+mod tests {
+  use std::error::Error;
+  use std::io;
+
+  #[derive(Debug)]
+  enum DisplayError {
+    Io(io::Error),
+    Other(String),
+  }
+
+  def_from_display! { DisplayError;
+    io::Error => Io, "I/O error: {}";
+    String    => Other;
+  }
+
+  #[test]
+  fn def_from_display_formats_each_variant() {
+    let err = DisplayError::from(io::Error::other("oops"));
+    assert_eq!(err.to_string(), "I/O error: oops");
+
+    // The format string defaults to `{:?}`.
+    let err: Box<dyn Error> = Box::new(DisplayError::from("oops".to_owned()));
+    assert_eq!(err.to_string(), "\"oops\"");
+  }
+
+  #[derive(Debug)]
+  enum BatchError {
+    Io(io::Error),
+    Fmt(::std::fmt::Error),
+    Other(String),
+  }
+
+  def_from!(BatchError, String => Other);
+  def_from_all!(BatchError; io::Error => Io, ::std::fmt::Error => Fmt);
+
+  #[test]
+  fn def_from_all_coexists_with_def_from() {
+    match BatchError::from(io::Error::from_raw_os_error(0)) {
+      BatchError::Io(ref e) if e.raw_os_error() == Some(0) => (),
+      err => panic!("unexpected variant: {:?}", err),
+    }
+    match BatchError::from(::std::fmt::Error) {
+      BatchError::Fmt(::std::fmt::Error) => (),
+      err => panic!("unexpected variant: {:?}", err),
+    }
+    match BatchError::from("oops".to_owned()) {
+      BatchError::Other(ref s) if s == "oops" => (),
+      err => panic!("unexpected variant: {:?}", err),
+    }
+  }
+
+  #[derive(Debug, PartialEq)]
+  enum MessageError {
+    Msg(String),
+  }
+
+  def_from_into!(MessageError, &'static str => Msg, String);
+
+  #[test]
+  fn def_from_into_converts_through_the_intermediate_type() {
+    assert_eq!(MessageError::from("oops"), MessageError::Msg("oops".to_owned()));
+  }
+
+  #[derive(Debug, PartialEq)]
+  enum Color {
+    Red,
+    Green,
+  }
+
+  #[derive(Debug, PartialEq)]
+  enum ColorError {
+    InvalidValue,
+  }
+
+  def_try_from! { Color, u8 => ColorError;
+    0 => Red,
+    1 => Green,
+  }
+
+  def_try_from!(Color, char => ColorError, |value| match value {
+    'r' => Ok(Color::Red),
+    'g' => Ok(Color::Green),
+    _ => Err(ColorError::InvalidValue),
+  });
+
+  #[test]
+  fn def_try_from_rejects_invalid_values() {
+    use std::convert::TryFrom;
+
+    assert_eq!(Color::try_from(0u8), Ok(Color::Red));
+    assert_eq!(Color::try_from(1u8), Ok(Color::Green));
+    assert_eq!(Color::try_from(2u8), Err(ColorError::InvalidValue));
+
+    assert_eq!(Color::try_from('g'), Ok(Color::Green));
+    assert_eq!(Color::try_from('x'), Err(ColorError::InvalidValue));
+  }
+}
@@ -0,0 +1,244 @@
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(test)]
+extern crate tempfile;
+#[macro_use]
+extern crate rust_miscs;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+
+use rustc_serialize::json::{self, Json};
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_root: String,
+  flag_dry_run: bool,
+  flag_force: bool,
+}
+
+const USAGE: &str = r#"
+Create the files and directories described by newline-delimited JSON records
+
+Usage:
+  mkdirtree [options]
+  mkdirtree (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --root=<DIR>      The directory which the paths are relative to [default: .]
+  -n --dry-run      Print the files and directories instead of creating them
+  -f --force        Overwrite the existing files
+
+Each record has the fields "path", "mode" (optional, in octal) and "content"
+(optional). The path ending with '/' is created as a directory, and the others
+as files.
+"#;
+
+def_error! {
+  MkdirtreeError {
+    Io(io::Error)           => "I/O error: {0}",
+    Json(json::ParserError) => "invalid JSON: {0}",
+    Other(String)           => "{0}",
+  }
+}
+
+// An entry to be created.
+#[derive(Debug)]
+struct Entry {
+  path: PathBuf,
+  is_dir: bool,
+  mode: Option<u32>,
+  content: Option<String>,
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  let stdin = io::stdin();
+  if let Err(e) = run(stdin.lock(), args) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
+
+fn run<R: BufRead>(reader: R, args: Args) -> Result<(), MkdirtreeError> {
+  let root = Path::new(&args.flag_root);
+  // The real path of the root, which the entries are checked against through the symlinks.
+  let real_root = if args.flag_dry_run {
+    None
+  } else {
+    fs::create_dir_all(root)?;
+    Some(fs::canonicalize(root)?)
+  };
+  for (i, line) in reader.lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let record = Json::from_str(&line)?;
+    let entry = parse_entry(root, &record).map_err(|e| format!("line {}: {}", i + 1, e))?;
+
+    if args.flag_dry_run {
+      println!("{} {}", if entry.is_dir { "mkdir" } else { "create" }, entry.path.display());
+      continue;
+    }
+    let real_root = real_root.as_ref().unwrap();
+    create(&entry, real_root, args.flag_force)
+      .map_err(|e| format!("{}: {}", entry.path.display(), e))?;
+  }
+  Ok(())
+}
+
+fn parse_entry(root: &Path, record: &Json) -> Result<Entry, String> {
+  let path = match record.find("path") {
+    Some(Json::String(path)) => path,
+    Some(_) => return Err("the path must be a string".to_owned()),
+    None => return Err("the field 'path' is not found".to_owned()),
+  };
+  let mode = match record.find("mode") {
+    Some(Json::String(mode)) => {
+      Some(u32::from_str_radix(mode, 8).map_err(|_| format!("invalid mode: {}", mode))?)
+    }
+    Some(&Json::Null) | None => None,
+    Some(_) => return Err("the mode must be an octal string".to_owned()),
+  };
+  let content = match record.find("content") {
+    Some(Json::String(content)) => Some(content.clone()),
+    Some(&Json::Null) | None => None,
+    Some(_) => return Err("the content must be a string".to_owned()),
+  };
+
+  Ok(Entry {
+    path: resolve(root, path)?,
+    is_dir: path.ends_with('/'),
+    mode,
+    content,
+  })
+}
+
+// Join the path to the root, rejecting the ones which escape from the root.
+//
+// The path is normalized lexically, so that the check does not depend on the existing files.
+fn resolve(root: &Path, path: &str) -> Result<PathBuf, String> {
+  let mut resolved = root.to_owned();
+  for component in Path::new(path).components() {
+    match component {
+      Component::Normal(name) => resolved.push(name),
+      Component::CurDir => (),
+      Component::ParentDir => {
+        resolved.pop();
+      }
+      Component::RootDir | Component::Prefix(_) => {
+        return Err(format!("absolute path is not allowed: {}", path));
+      }
+    }
+  }
+  if !resolved.starts_with(root) || resolved == root {
+    return Err(format!("the path escapes from the root: {}", path));
+  }
+  Ok(resolved)
+}
+
+// Check that the path does not escape from the root through a symlink.
+//
+// The nearest existing ancestor of the path is resolved, since the rest is not created yet.
+fn check_inside(real_root: &Path, path: &Path) -> io::Result<()> {
+  let existing = path.ancestors()
+    .find(|p| fs::symlink_metadata(p).is_ok())
+    .unwrap_or(path);
+  if !fs::canonicalize(existing)?.starts_with(real_root) {
+    return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                              "the path escapes from the root through a symlink"));
+  }
+  Ok(())
+}
+
+fn create(entry: &Entry, real_root: &Path, force: bool) -> io::Result<()> {
+  check_inside(real_root, &entry.path)?;
+  if entry.is_dir {
+    fs::create_dir_all(&entry.path)?;
+  } else {
+    if let Some(parent) = entry.path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+      .write(true)
+      .create_new(!force)
+      .create(true)
+      .truncate(true)
+      .open(&entry.path)
+      .map_err(|e| if e.kind() == io::ErrorKind::AlreadyExists {
+        io::Error::new(e.kind(), "the file already exists (use --force to overwrite it)")
+      } else {
+        e
+      })?;
+    if let Some(ref content) = entry.content {
+      file.write_all(content.as_bytes())?;
+    }
+  }
+  if let Some(mode) = entry.mode {
+    set_mode(&entry.path, mode)?;
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+// The permissions other than read-only are not supported on non-Unix platforms.
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn mkdirtree(root: &Path, input: &str, force: bool) -> Result<(), MkdirtreeError> {
+    let args = Args {
+      flag_root: root.to_str().unwrap().to_owned(),
+      flag_dry_run: false,
+      flag_force: force,
+    };
+    run(Cursor::new(input), args)
+  }
+
+  #[test]
+  fn existing_files_are_overwritten_only_with_force() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = r#"{"path": "a/b.txt", "content": "new"}"#;
+    fs::create_dir(dir.path().join("a")).unwrap();
+    fs::write(dir.path().join("a/b.txt"), "old").unwrap();
+
+    assert!(mkdirtree(dir.path(), input, false).is_err());
+    assert_eq!(fs::read_to_string(dir.path().join("a/b.txt")).unwrap(), "old");
+
+    mkdirtree(dir.path(), input, true).unwrap();
+    assert_eq!(fs::read_to_string(dir.path().join("a/b.txt")).unwrap(), "new");
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn symlinks_out_of_the_root_are_rejected() {
+    let root = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    ::std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+
+    assert!(mkdirtree(root.path(), r#"{"path": "link/a.txt"}"#, true).is_err());
+    assert!(mkdirtree(root.path(), r#"{"path": "link/sub/"}"#, true).is_err());
+    assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+  }
+}
@@ -0,0 +1,107 @@
+//! Patterns matched against file names.
+
+use std::ops::Range;
+use std::path::Path;
+
+use glob::{self, MatchOptions};
+use regex::{self, Regex};
+
+/// A regular expression matched against the file name of a path.
+///
+/// The empty pattern matches nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Pattern {
+  re: Option<Regex>,
+  // The file names which never match, even if `re` matches them.
+  exception: Option<Regex>,
+}
+
+impl Pattern {
+  /// Compile the pattern. An empty string gives the empty pattern.
+  pub fn new(s: &str) -> Result<Pattern, regex::Error> {
+    if s.is_empty() {
+      return Ok(Pattern::empty());
+    }
+    Regex::new(s).map(|re| Pattern { re: Some(re), exception: None })
+  }
+
+  pub fn empty() -> Pattern {
+    Pattern::default()
+  }
+
+  /// Exclude the file names matching `exception` from the matches of the pattern.
+  pub fn except(self, exception: &str) -> Result<Pattern, regex::Error> {
+    Ok(Pattern { exception: Pattern::new(exception)?.re, ..self })
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.re.is_none()
+  }
+
+  /// Return whether the file name of the path matches the pattern.
+  pub fn matches(&self, path: &Path) -> bool {
+    self.find(path).is_some()
+  }
+
+  /// Return the range of the first match in the file name of the path.
+  pub fn find(&self, path: &Path) -> Option<Range<usize>> {
+    let re = self.re.as_ref()?;
+    let name = path.file_name()?.to_string_lossy();
+    if self.exception.as_ref().is_some_and(|exception| exception.is_match(&name)) {
+      return None;
+    }
+    re.find(&name).map(|m| m.start()..m.end())
+  }
+}
+
+/// A glob pattern (e.g. `*.rs` or `src/**/*.toml`).
+///
+/// The pattern is matched against the path relative to the root if it contains `/`,
+/// and against the file name otherwise. The empty pattern matches nothing.
+#[derive(Debug, Clone, Default)]
+pub struct GlobPattern(Option<glob::Pattern>);
+
+impl GlobPattern {
+  pub fn new(s: &str) -> Result<GlobPattern, glob::PatternError> {
+    if s.is_empty() {
+      return Ok(GlobPattern::empty());
+    }
+    glob::Pattern::new(s).map(|pattern| GlobPattern(Some(pattern)))
+  }
+
+  pub fn empty() -> GlobPattern {
+    GlobPattern(None)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_none()
+  }
+
+  /// Return whether the path, relative to the root of the traversal, matches the pattern.
+  pub fn matches(&self, relative: &Path) -> bool {
+    let pattern = match self.0 {
+      Some(ref pattern) => pattern,
+      None => return false,
+    };
+    if pattern.as_str().contains('/') {
+      let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+      pattern.matches_path_with(relative, options)
+    } else {
+      relative.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exception_is_never_matched() {
+    let pattern = Pattern::new(r"^\.").unwrap().except(r"^\.git$").unwrap();
+    assert!(pattern.matches(Path::new("a/.env")));
+    assert!(!pattern.matches(Path::new("a/.git")));
+    assert!(pattern.matches(Path::new("a/.gitignore")));
+    assert!(!pattern.matches(Path::new("a/b")));
+  }
+}
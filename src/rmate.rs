@@ -0,0 +1,462 @@
+//! The client side of the rmate protocol (as used by TextMate and its ports), shared by `rsmate`.
+//!
+//! The client sends the files with `open` commands, and the editor sends back `save` and
+//! `close` commands with the tokens of the files.
+
+use std::io::{self, BufRead, Read, Write};
+use std::string::FromUtf8Error;
+use std::num::ParseIntError;
+use std::fs::{self, canonicalize, File};
+use std::path::{Path, PathBuf};
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+def_error! {
+  pub Error {
+    Io(io::Error)           => "I/O error: {0}",
+    FromUtf8(FromUtf8Error) => "invalid UTF-8 data: {0}",
+    ParseInt(ParseIntError) => "invalid integer: {0}",
+    Parse(String)           => "parse error: {0}",
+  }
+}
+
+pub type RMateResult<T> = Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Cmd {
+  Save,
+  Close,
+}
+
+#[derive(Debug)]
+pub struct Command {
+  pub cmd: Cmd,
+  pub token: String,
+  pub data: String,
+}
+
+/// The parameters of an `open` command.
+#[derive(Debug, Clone)]
+pub struct OpenOptions<'a> {
+  /// The path of the file, which is also sent as the token.
+  pub name: &'a str,
+  /// The name shown in the editor.
+  pub display_name: &'a str,
+  /// The TextMate grammar name. It is detected from the extension of `name` if not given.
+  pub type_hint: Option<&'a str>,
+  /// The line and column where the cursor is placed.
+  pub selection: Option<(u32, u32)>,
+}
+
+impl<'a> OpenOptions<'a> {
+  pub fn new(name: &'a str) -> OpenOptions<'a> {
+    OpenOptions {
+      name,
+      display_name: name,
+      type_hint: None,
+      selection: None,
+    }
+  }
+}
+
+/// Send the file `opts.name`.
+pub fn send_open<W: Write>(stream: &mut W, opts: &OpenOptions) -> RMateResult<()> {
+  let real_path = canonicalize(opts.name)?;
+  let file = File::open(opts.name)?;
+
+  // Mapping a zero-length file fails on some platforms, so it is sent without mapping.
+  if file.metadata()?.len() == 0 {
+    return write_open(stream, opts, Some(&real_path), &[]);
+  }
+
+  // Safety: the file is kept open while the mapping is alive, the mapping does not outlive
+  // this function, and the file is not expected to be modified while it is being sent.
+  let file_mmap = unsafe { Mmap::map(&file)? };
+  write_open(stream, opts, Some(&real_path), &file_mmap)
+}
+
+/// Send the data read from somewhere other than a file (e.g. stdin), instead of `opts.name`.
+pub fn send_open_data<W: Write>(stream: &mut W,
+                                opts: &OpenOptions,
+                                data: &[u8])
+                                -> RMateResult<()> {
+  write_open(stream, opts, None, data)
+}
+
+fn write_open<W: Write>(stream: &mut W,
+                        opts: &OpenOptions,
+                        real_path: Option<&Path>,
+                        data: &[u8])
+                        -> RMateResult<()> {
+  stream.write_all(b"open\n")?;
+  stream.write_all(format!("display-name: {}\n", opts.display_name).as_bytes())?;
+  let file_type = opts.type_hint.or_else(|| {
+    Path::new(opts.name).extension().and_then(|ext| ext.to_str()).and_then(detect_file_type)
+  });
+  if let Some(file_type) = file_type {
+    stream.write_all(format!("file-type: {}\n", file_type).as_bytes())?;
+  }
+  if let Some(real_path) = real_path {
+    stream.write_all(format!("real-path: {}\n", real_path.display()).as_bytes())?;
+  }
+  stream.write_all(b"data-on-save: yes\n")?;
+  stream.write_all(b"re-activate: yes\n")?;
+  if let Some((line, col)) = opts.selection {
+    stream.write_all(format!("selection: {}:{}\n", line, col).as_bytes())?;
+  }
+  stream.write_all(format!("token: {}\n", opts.name).as_bytes())?;
+  stream.write_all(format!("data: {}\n", data.len()).as_bytes())?;
+  stream.write_all(data)?;
+  stream.write_all(b"\n.\n")?;
+  stream.flush()?;
+
+  Ok(())
+}
+
+// The TextMate grammar names of the common file extensions.
+const FILE_TYPES: &[(&str, &str)] = &[
+  ("c", "source.c"),
+  ("h", "source.c"),
+  ("cpp", "source.c++"),
+  ("hpp", "source.c++"),
+  ("go", "source.go"),
+  ("java", "source.java"),
+  ("js", "source.js"),
+  ("json", "source.json"),
+  ("md", "text.html.markdown"),
+  ("py", "source.python"),
+  ("rb", "source.ruby"),
+  ("rs", "source.rust"),
+  ("sh", "source.shell"),
+  ("toml", "source.toml"),
+  ("yml", "source.yaml"),
+  ("yaml", "source.yaml"),
+];
+
+/// Return the TextMate grammar name corresponding to the file extension.
+pub fn detect_file_type(ext: &str) -> Option<&'static str> {
+  FILE_TYPES.iter().find(|&&(e, _)| e == ext).map(|&(_, file_type)| file_type)
+}
+
+/// Parse the selection in the form of `LINE` or `LINE:COL`. The column defaults to 1.
+pub fn parse_selection(s: &str) -> RMateResult<(u32, u32)> {
+  let mut parts = s.splitn(2, ':');
+  let line = parts.next().unwrap_or("").trim().parse()?;
+  let col = match parts.next() {
+    Some(col) => col.trim().parse()?,
+    None => 1,
+  };
+  Ok((line, col))
+}
+
+enum ReadCmd {
+  Command(Command),
+  Empty,
+  Eof,
+}
+
+// Read a line, without the trailing newline. `None` is returned at EOF.
+fn read_line<R: BufRead>(reader: &mut R) -> RMateResult<Option<String>> {
+  let mut line = String::new();
+  let len = reader.read_line(&mut line)?;
+  if len == 0 {
+    return Ok(None);
+  }
+  Ok(Some(line.trim_end().to_owned()))
+}
+
+// Extract the value of a `key: value` line. Everything after the first colon is the value.
+fn field_value(line: &str) -> Option<&str> {
+  line.split_once(':').map(|(_, value)| value.trim())
+}
+
+/// The default limit of the size of the data in a command.
+pub const MAX_DATA_SIZE: usize = 64 * 1024 * 1024;
+
+// Read a command, whose data must not be larger than `max_data_size` bytes.
+fn read_command<R: BufRead>(reader: &mut R, max_data_size: usize) -> RMateResult<ReadCmd> {
+  let cmd = match read_line(reader)? {
+    Some(cmd) => cmd,
+    None => return Ok(ReadCmd::Eof),
+  };
+  let cmd = match cmd.as_str() {
+    "save" => Cmd::Save,
+    "close" => Cmd::Close,
+    _ => return Ok(ReadCmd::Empty),
+  };
+
+  let token = read_line(reader)?
+    .as_ref()
+    .and_then(|line| field_value(line))
+    .ok_or(Error::Parse("missing token field".to_owned()))?
+    .to_owned();
+
+  let len = read_line(reader)?
+    .as_ref()
+    .and_then(|line| field_value(line))
+    .ok_or(Error::Parse("missing data-length field".to_owned()))?
+    .to_owned();
+  let len = len.parse::<usize>()?;
+  // The buffer is allocated before the data arrives, so a bogus length must be rejected here.
+  if len > max_data_size {
+    return Err(Error::Parse("data size too large".to_owned()));
+  }
+
+  let mut buf = vec![0u8; len];
+  reader.read_exact(buf.as_mut_slice())?;
+  let data = String::from_utf8(buf)?;
+
+  Ok(ReadCmd::Command(Command {
+    cmd,
+    token,
+    data,
+  }))
+}
+
+/// The callbacks invoked by `handle_commands` for the commands sent by the editor.
+pub trait CommandHandler {
+  /// The file `token` is saved in the editor, with the content `data`.
+  fn on_save(&mut self, token: &str, data: &[u8]) -> RMateResult<()>;
+
+  /// The file `token` is closed in the editor.
+  fn on_close(&mut self, token: &str) -> RMateResult<()>;
+}
+
+/// Write the saved content to the file named by the token.
+#[derive(Debug, Default)]
+pub struct FilesystemHandler {
+  /// The suffix of the backup file, if the existing file is copied before overwriting it.
+  pub backup: Option<String>,
+}
+
+impl FilesystemHandler {
+  pub fn new() -> FilesystemHandler {
+    FilesystemHandler::default()
+  }
+}
+
+impl CommandHandler for FilesystemHandler {
+  fn on_save(&mut self, token: &str, data: &[u8]) -> RMateResult<()> {
+    let path = Path::new(token);
+    if let Some(ref suffix) = self.backup {
+      backup_file(path, suffix)?;
+    }
+    write_atomic(path, data)?;
+    verify_checksum(path, data)
+  }
+
+  fn on_close(&mut self, _token: &str) -> RMateResult<()> {
+    // do nothing
+    Ok(())
+  }
+}
+
+/// Read the commands until the connection is closed, and pass them to the handler.
+///
+/// A command whose data is larger than `max_data_size` bytes (usually `MAX_DATA_SIZE`) is
+/// an error.
+pub fn handle_commands<R, H>(mut reader: R,
+                             handler: &mut H,
+                             max_data_size: usize)
+                             -> RMateResult<()>
+  where R: BufRead,
+        H: CommandHandler
+{
+  loop {
+    let command = match read_command(&mut reader, max_data_size)? {
+      ReadCmd::Empty => continue,
+      ReadCmd::Eof => break,
+      ReadCmd::Command(command) => command,
+    };
+    debug!("{:?}", command);
+
+    match command.cmd {
+      Cmd::Save => handler.on_save(&command.token, command.data.as_bytes())?,
+      Cmd::Close => handler.on_close(&command.token)?,
+    }
+  }
+  Ok(())
+}
+
+// Copy the existing file to `<path><suffix>`, or `<path><suffix>.N` if it already exists.
+fn backup_file(path: &Path, suffix: &str) -> io::Result<()> {
+  if !path.is_file() {
+    return Ok(());
+  }
+
+  let mut base = path.as_os_str().to_owned();
+  base.push(suffix);
+  let mut backup = PathBuf::from(&base);
+  let mut n = 1;
+  while backup.exists() {
+    let mut name = base.clone();
+    name.push(format!(".{}", n));
+    backup = PathBuf::from(name);
+    n += 1;
+  }
+
+  fs::copy(path, backup).map(|_| ())
+}
+
+// Compare the SHA-256 of the received data with the one of the file just written.
+//
+// This is a post-write sanity check, not a part of the protocol: the protocol carries no
+// checksum, so it only detects the corruption which occurs while writing the file.
+fn verify_checksum(path: &Path, data: &[u8]) -> RMateResult<()> {
+  let mut written = Vec::with_capacity(data.len());
+  File::open(path)?.read_to_end(&mut written)?;
+  if Sha256::digest(data) != Sha256::digest(&written) {
+    return Err(Error::Parse("checksum mismatch".to_owned()));
+  }
+  Ok(())
+}
+
+// Write the data to a temporary file in the same directory and rename it over the
+// destination, so that a crash in the middle of writing never leaves a truncated file.
+//
+// The permissions of the existing file are kept, and a symbolic link is resolved so that
+// its target is replaced rather than the link itself.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+  let path = match canonicalize(path) {
+    Ok(path) => path,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => path.to_owned(),
+    Err(e) => return Err(e),
+  };
+  let dir = match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+
+  // The temporary file is removed when dropped, unless it is persisted.
+  let mut file = NamedTempFile::new_in(dir)?;
+  file.write_all(data)?;
+  match fs::metadata(&path) {
+    Ok(metadata) => file.as_file().set_permissions(metadata.permissions())?,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+    Err(e) => return Err(e),
+  }
+  file.as_file().sync_all()?;
+  file.persist(&path).map_err(|e| e.error)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile;
+
+  #[test]
+  fn write_atomic_replaces_the_content() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.txt");
+    fs::write(&path, "old").unwrap();
+    // A file with the same name as a temporary one must be left untouched.
+    fs::write(dir.path().join("a.txt.tmp"), "user").unwrap();
+
+    write_atomic(&path, b"new").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert_eq!(fs::read_to_string(dir.path().join("a.txt.tmp")).unwrap(), "user");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn write_atomic_keeps_the_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("script.sh");
+    fs::write(&path, "old").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    write_atomic(&path, b"new").unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o755);
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn write_atomic_follows_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("target.txt");
+    let link = dir.path().join("link.txt");
+    fs::write(&target, "old").unwrap();
+    symlink(&target, &link).unwrap();
+
+    write_atomic(&link, b"new").unwrap();
+    assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+  }
+
+  // Record the commands instead of touching the filesystem.
+  #[derive(Default)]
+  struct RecordingHandler {
+    commands: Vec<(&'static str, String, String)>,
+  }
+
+  impl CommandHandler for RecordingHandler {
+    fn on_save(&mut self, token: &str, data: &[u8]) -> RMateResult<()> {
+      let data = String::from_utf8(data.to_owned())?;
+      self.commands.push(("save", token.to_owned(), data));
+      Ok(())
+    }
+
+    fn on_close(&mut self, token: &str) -> RMateResult<()> {
+      self.commands.push(("close", token.to_owned(), String::new()));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn commands_are_passed_to_the_handler() {
+    let input = "save\ntoken: a.txt\ndata: 6\nhello\n\n\nclose\ntoken: a.txt\ndata: 0\n\n";
+    let mut handler = RecordingHandler::default();
+    handle_commands(input.as_bytes(), &mut handler, MAX_DATA_SIZE).unwrap();
+    assert_eq!(handler.commands,
+               vec![("save", "a.txt".to_owned(), "hello\n".to_owned()),
+                    ("close", "a.txt".to_owned(), String::new())]);
+  }
+
+  #[test]
+  fn sent_file_is_read_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.txt");
+    fs::write(&path, "content").unwrap();
+    let name = path.to_str().unwrap();
+
+    let mut sent = Vec::new();
+    send_open(&mut sent, &OpenOptions::new(name)).unwrap();
+    let sent = String::from_utf8(sent).unwrap();
+    assert!(sent.starts_with("open\n"), "{}", sent);
+    assert!(sent.contains(&format!("token: {}\n", name)), "{}", sent);
+    assert!(sent.ends_with("data: 7\ncontent\n.\n"), "{}", sent);
+
+    // The server echoes the data back as a `save` command.
+    let body = &sent[sent.find("token:").unwrap()..sent.len() - 2];
+    let mut handler = RecordingHandler::default();
+    handle_commands(format!("save\n{}", body).as_bytes(), &mut handler, MAX_DATA_SIZE).unwrap();
+    assert_eq!(handler.commands, vec![("save", name.to_owned(), "content".to_owned())]);
+  }
+
+  #[test]
+  fn data_larger_than_the_limit_is_rejected() {
+    let input = "save\ntoken: a.txt\ndata: 99999999999\n";
+    let mut handler = RecordingHandler::default();
+    match handle_commands(input.as_bytes(), &mut handler, MAX_DATA_SIZE) {
+      Err(Error::Parse(_)) => (),
+      result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(handler.commands.is_empty());
+  }
+
+  #[test]
+  fn data_within_the_limit_is_accepted() {
+    let input = "save\ntoken: a.txt\ndata: 4\nabcd\n";
+    let mut handler = RecordingHandler::default();
+    handle_commands(input.as_bytes(), &mut handler, 4).unwrap();
+    assert!(handle_commands(input.as_bytes(), &mut handler, 3).is_err());
+    assert_eq!(handler.commands, vec![("save", "a.txt".to_owned(), "abcd".to_owned())]);
+  }
+}
@@ -1,247 +1,776 @@
 extern crate rustc_serialize;
 extern crate docopt;
-extern crate memmap;
 #[cfg(not(windows))]
 extern crate nix;
-
+extern crate rustls;
+extern crate rustls_native_certs;
+extern crate rustls_pemfile;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate notify;
+extern crate socket2;
+extern crate sha2;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+#[macro_use]
+extern crate rust_miscs;
+#[cfg(test)]
+extern crate tempfile;
+
+use std::convert::TryFrom;
+use std::env;
+use std::collections::HashMap;
+use std::fs::{canonicalize, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use docopt::Docopt;
+use log::LevelFilter;
+use notify::{EventKind, RecursiveMode, Watcher};
+use sha2::{Digest, Sha256};
+use socket2::{SockRef, TcpKeepalive};
+use rust_miscs::rmate::{self, CommandHandler, FilesystemHandler, OpenOptions};
 #[cfg(not(windows))]
 use nix::unistd::{fork, ForkResult};
 
-const USAGE: &'static str = "
+def_error! {
+  RsmateError {
+    RMate(rmate::Error)  => "{0}",
+    Io(io::Error)        => "I/O error: {0}",
+    Tls(rustls::Error)   => "TLS error: {0}",
+    Watch(notify::Error) => "watch error: {0}",
+  }
+}
+
+type RsmateResult<T> = Result<T, RsmateError>;
+
+const USAGE: &str = "
 Rmate client written in Rust.
 
 Usage:
-  rmate [--host=<H> --port=<P> -w] <name>
+  rmate [options] <name>...
+  rmate [options] --stdin=<NAME>
   rmate -h | --help
   rmate -v | --version
 
 Options:
   -h --help     Show this message.
   -v --version  Print version of information.
-  --host=<H>    The hostname of Rmate server [default: localhost].
-  --port=<P>    The port number of Rmate server [default: 52689].
+  --verbose     Print the debug messages.
+  --host=<H>    The hostname of Rmate server (default: localhost).
+  --port=<P>    The port number of Rmate server (default: 52689).
+  --socket=<PATH>  Connect to Rmate server via the Unix domain socket, instead of TCP.
+  --retry=<N>   Retry to connect N times when the connection fails [default: 0].
+  --retry-delay=<MS>  The delay before the first retry, in milliseconds. The delay is
+                      doubled on each retry [default: 1000].
   -w --wait     Wait for file to be closed by Textmate.
+  --fork        Fork into background unless --wait is given (default).
+  --no-fork     Never fork, e.g. where fork() is not allowed.
+  --stdin=<NAME>  Send the content read from stdin, with NAME as its display name.
+                  The content saved in the editor is written to stdout, so it
+                  implies --no-fork.
+  --watch       Stay alive and re-send the file each time it is modified locally.
+  -b --backup   Copy the existing file to a backup before overwriting it on save.
+  --backup-suffix=<SUFFIX>  The suffix of the backup file [default: .bak].
+  --select=<LINE[:COL]>  Open the file with the cursor at the given position.
+  --type-hint=<TYPE>  The file type sent to the server (e.g. source.rust). If omitted,
+                      it is detected from the extension of the file.
+  --connect-timeout=<MS>  Give up connecting after MS milliseconds (default: the OS timeout).
+  --keepalive=<SECS>  Enable TCP keepalive, probing after SECS seconds of idle.
+  --max-data-size=<BYTES>  The largest file accepted on save (default: 64 MiB).
+  --tls         Connect to Rmate server over TLS.
+  --ca-cert=<PATH>  The CA certificate (PEM) to verify the server with, instead of
+                    the system trust roots.
+
+The default values of --host, --port and --wait can be set in the configuration file
+($XDG_CONFIG_HOME/rmate/config.toml, or ~/.rmate.rc if XDG_CONFIG_HOME is not set).
 ";
 
-const HOST: &'static str = "localhost";
+const HOST: &str = "localhost";
 const PORT: u32 = 52689;
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Deserialize)]
 struct Args {
-  arg_name: Option<String>,
-  arg_host: Option<String>,
-  arg_port: Option<u32>,
+  arg_name: Vec<String>,
+  flag_host: Option<String>,
+  flag_port: Option<u32>,
+  flag_socket: Option<String>,
+  flag_retry: u32,
+  flag_retry_delay: u64,
   flag_wait: bool,
+  flag_fork: bool,
+  flag_no_fork: bool,
+  flag_stdin: Option<String>,
+  flag_verbose: bool,
+  flag_watch: bool,
+  flag_backup: bool,
+  flag_backup_suffix: String,
+  flag_select: Option<String>,
+  flag_type_hint: Option<String>,
+  flag_connect_timeout: Option<u64>,
+  flag_keepalive: Option<u64>,
+  flag_max_data_size: Option<usize>,
+  flag_tls: bool,
+  flag_ca_cert: Option<String>,
+}
+
+// The contents of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+  host: Option<String>,
+  port: Option<u32>,
+  wait: Option<bool>,
+}
+
+impl Config {
+  fn path() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+      Some(dir) => Some(PathBuf::from(dir).join("rmate/config.toml")),
+      None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".rmate.rc")),
+    }
+  }
+
+  // Load the configuration file.
+  //
+  // A missing file is silently skipped, and a malformed one is reported as a warning.
+  fn load() -> Config {
+    let path = match Config::path() {
+      Some(path) => path,
+      None => return Config::default(),
+    };
+    Config::read(&path).unwrap_or_else(|e| {
+      eprintln!("warning: {}", e);
+      Config::default()
+    })
+  }
+
+  // Read the configuration file at `path`, where a missing file gives the default values.
+  fn read(path: &Path) -> Result<Config, String> {
+    let mut content = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+      Ok(_) => (),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+      Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+    }
+    toml::from_str(&content).map_err(|e| format!("failed to parse {}: {}", path.display(), e))
+  }
+}
+
+#[derive(Debug)]
+pub enum Transport {
+  Tcp { host: String, port: u32 },
+  #[cfg(unix)]
+  Unix { path: PathBuf },
 }
 
 #[derive(Debug)]
 pub struct Options {
-  pub name: String,
-  pub host: String,
-  pub port: u32,
+  pub names: Vec<String>,
+  // The display name of the content read from stdin, instead of the files.
+  pub stdin: Option<String>,
+  pub transport: Transport,
+  pub retry: u32,
+  pub retry_delay: u64,
+  pub connect_timeout: Option<u64>,
+  pub keepalive: Option<u64>,
+  pub max_data_size: usize,
   pub wait: bool,
+  pub no_fork: bool,
+  pub verbose: bool,
+  pub watch: bool,
+  // The suffix of the backup file, if the backup is enabled.
+  pub backup: Option<String>,
+  // The line and column to be selected on open.
+  pub selection: Option<(u32, u32)>,
+  pub type_hint: Option<String>,
+  pub tls: bool,
+  pub ca_cert: Option<String>,
 }
 
 pub fn parse_options() -> Options {
   let args: Args = Docopt::new(USAGE)
-    .and_then(|d| d.decode())
+    .map(|d| d.version(Some(env!("CARGO_PKG_VERSION").to_owned())))
+    .and_then(|d| d.deserialize())
     .unwrap_or_else(|e| e.exit());
 
-  if args.arg_name.is_none() {
+  if args.arg_name.is_empty() && args.flag_stdin.is_none() {
     println!("filename is not given.");
     exit(1);
   }
+  if args.flag_fork && args.flag_no_fork {
+    println!("--fork cannot be used with --no-fork.");
+    exit(1);
+  }
+  if args.flag_stdin.is_some() && args.flag_fork {
+    println!("--fork cannot be used with --stdin.");
+    exit(1);
+  }
+  if args.flag_stdin.is_some() && args.flag_watch {
+    println!("--watch cannot be used with --stdin.");
+    exit(1);
+  }
+
+  // The name is used as the token of the file, so it must be unique.
+  let mut names: Vec<String> = Vec::with_capacity(args.arg_name.len());
+  for name in args.arg_name {
+    if !names.contains(&name) {
+      names.push(name);
+    }
+  }
+
+  // The command line options take precedence over the configuration file.
+  let config = Config::load();
+
+  let transport = match args.flag_socket {
+    Some(path) => {
+      if args.flag_tls {
+        println!("--tls cannot be used with --socket.");
+        exit(1);
+      }
+      unix_transport(path)
+    }
+    None => {
+      Transport::Tcp {
+        host: args.flag_host.or(config.host).unwrap_or(HOST.to_owned()),
+        port: args.flag_port.or(config.port).unwrap_or(PORT),
+      }
+    }
+  };
+
+  let selection = args.flag_select.map(|s| {
+    rmate::parse_selection(&s).unwrap_or_else(|e| {
+      println!("invalid --select: {}", e);
+      exit(1);
+    })
+  });
+
+  // The forked process would write the saved content to stdout after the shell has moved on.
+  let no_fork = args.flag_no_fork || args.flag_stdin.is_some();
 
   Options {
-    name: args.arg_name.unwrap(),
-    host: args.arg_host.unwrap_or(HOST.to_owned()),
-    port: args.arg_port.unwrap_or(PORT),
-    wait: args.flag_wait,
+    names,
+    stdin: args.flag_stdin,
+    transport,
+    retry: args.flag_retry,
+    retry_delay: args.flag_retry_delay,
+    connect_timeout: args.flag_connect_timeout,
+    keepalive: args.flag_keepalive,
+    max_data_size: args.flag_max_data_size.unwrap_or(rmate::MAX_DATA_SIZE),
+    wait: args.flag_wait || config.wait.unwrap_or(false),
+    no_fork,
+    verbose: args.flag_verbose,
+    watch: args.flag_watch,
+    backup: if args.flag_backup { Some(args.flag_backup_suffix) } else { None },
+    selection,
+    type_hint: args.flag_type_hint,
+    tls: args.flag_tls,
+    ca_cert: args.flag_ca_cert,
   }
 }
 
+#[cfg(unix)]
+fn unix_transport(path: String) -> Transport {
+  Transport::Unix { path: PathBuf::from(path) }
+}
+
+#[cfg(not(unix))]
+fn unix_transport(_path: String) -> Transport {
+  println!("--socket is not supported on this platform.");
+  exit(1);
+}
+
 #[cfg(windows)]
 fn _fork() {}
 
 #[cfg(not(windows))]
 fn _fork() {
-  match fork() {
+  // SAFETY: this runs in `main` before any thread is spawned, so the child process does not
+  // inherit the locks held by other threads.
+  match unsafe { fork() } {
     Ok(ForkResult::Parent { .. }) => exit(0),
     Ok(ForkResult::Child) => (),
-    Err(_) => panic!("fork failed"),
+    Err(e) => {
+      error!("fork failed: {}", e);
+      exit(1);
+    }
   }
 }
 
 fn main() {
   let options = parse_options();
-  println!("{:?}", options);
 
-  if !options.wait {
+  let level = if options.verbose {
+    LevelFilter::Debug
+  } else {
+    LevelFilter::Warn
+  };
+  env_logger::Builder::new().filter_level(level).init();
+  debug!("{:?}", options);
+
+  if !options.wait && !options.no_fork {
     _fork()
   }
 
-  // create a connection to Rmate server.
-  let mut stream =
-    std::net::TcpStream::connect(format!("{}:{}", options.host, options.port).as_str()).unwrap();
+  if let Err(e) = run(&options) {
+    eprintln!("error: {}", e);
+    exit(1);
+  }
+}
 
-  // send all of the content to the server.
-  rmate::send_open(&mut stream, options.name.as_str()).unwrap();
+fn run(options: &Options) -> RsmateResult<()> {
+  if options.watch {
+    return watch(options);
+  }
+  let stream = connect_and_send(options)?;
+  if options.stdin.is_some() {
+    session(stream, &mut StdoutHandler, options.max_data_size)?;
+  } else {
+    session(stream, &mut filesystem_handler(options), options.max_data_size)?;
+  }
+  Ok(())
+}
 
-  // handle all commands
-  let mut reader = std::io::BufReader::new(stream);
+fn filesystem_handler(options: &Options) -> FilesystemHandler {
+  let mut handler = FilesystemHandler::new();
+  handler.backup = options.backup.clone();
+  handler
+}
 
-  let servername = {
-    use std::io::BufRead;
-    let mut reader = &mut reader;
-    let mut line = String::new();
-    reader.read_line(&mut line).unwrap();
-    line.trim_right().to_owned()
-  };
-  println!("{:?}", servername);
+// Write the saved content to stdout, for the content read from stdin.
+//
+// The token is the display name given by `--stdin`, which is never used as a path.
+struct StdoutHandler;
+
+impl CommandHandler for StdoutHandler {
+  fn on_save(&mut self, _token: &str, data: &[u8]) -> rmate::RMateResult<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(data)?;
+    stdout.flush()?;
+    Ok(())
+  }
 
-  rmate::handle_commands(reader).unwrap();
+  fn on_close(&mut self, _token: &str) -> rmate::RMateResult<()> {
+    Ok(())
+  }
 }
 
-mod rmate {
-  use std::io::{self, BufRead, Write};
-  use std::string::FromUtf8Error;
-  use std::num::ParseIntError;
-  use std::fs::canonicalize;
-  use memmap::{Mmap, Protection};
+// A bidirectional connection to Rmate server.
+trait Stream: Read + Write + Send {}
 
-  #[derive(Debug)]
-  pub enum Error {
-    Io(io::Error),
-    FromUtf8(FromUtf8Error),
-    ParseInt(ParseIntError),
-    Parse(String),
-  }
+impl<S: Read + Write + Send> Stream for S {}
+
+// The socket under a connection, by which the connection is shut down from another thread.
+enum Socket {
+  Tcp(TcpStream),
+  #[cfg(unix)]
+  Unix(UnixStream),
+}
 
-  impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Error {
-      Error::Io(err)
+impl Socket {
+  fn shutdown(&self) -> io::Result<()> {
+    match *self {
+      Socket::Tcp(ref stream) => stream.shutdown(Shutdown::Both),
+      #[cfg(unix)]
+      Socket::Unix(ref stream) => stream.shutdown(Shutdown::Both),
     }
   }
+}
 
-  impl From<FromUtf8Error> for Error {
-    fn from(err: FromUtf8Error) -> Error {
-      Error::FromUtf8(err)
+// Create a connection to Rmate server.
+fn open_connection(options: &Options) -> RsmateResult<(Box<dyn Stream>, Socket)> {
+  match options.transport {
+    Transport::Tcp { ref host, port } => {
+      let timeout = options.connect_timeout.map(Duration::from_millis);
+      let stream = connect_with_retry(host, port, options.retry, options.retry_delay, timeout)?;
+      // The session may be idle for a long time while the file is edited, so the connection
+      // would be dropped silently by the middleboxes without keepalive.
+      if let Some(secs) = options.keepalive {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+        SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+      }
+      let socket = Socket::Tcp(stream.try_clone()?);
+      if options.tls {
+        Ok((Box::new(connect_tls(stream, host, options)?), socket))
+      } else {
+        Ok((Box::new(stream), socket))
+      }
+    }
+    #[cfg(unix)]
+    Transport::Unix { ref path } => {
+      let stream = UnixStream::connect(path)?;
+      let socket = Socket::Unix(stream.try_clone()?);
+      Ok((Box::new(stream), socket))
     }
   }
+}
 
-  impl From<ParseIntError> for Error {
-    fn from(err: ParseIntError) -> Error {
-      Error::ParseInt(err)
+fn open_options<'a>(options: &'a Options, name: &'a str) -> OpenOptions<'a> {
+  OpenOptions {
+    type_hint: options.type_hint.as_deref(),
+    selection: options.selection,
+    ..OpenOptions::new(name)
+  }
+}
+
+// Create a connection to Rmate server and send all of the contents to it.
+fn connect_and_send(options: &Options) -> RsmateResult<Box<dyn Stream>> {
+  let (mut stream, _) = open_connection(options)?;
+  match options.stdin {
+    Some(ref name) => {
+      let stdin = io::stdin();
+      send_stdin(&mut stream, stdin.lock(), &open_options(options, name))?;
+    }
+    None => {
+      for name in &options.names {
+        rmate::send_open(&mut stream, &open_options(options, name))?;
+      }
     }
   }
+  Ok(stream)
+}
 
-  pub type RMateResult<T> = Result<T, Error>;
+// Send the content read from `input` (i.e. stdin) with the name given by `--stdin`.
+fn send_stdin<W: Write, R: Read>(stream: &mut W,
+                                 mut input: R,
+                                 opts: &OpenOptions)
+                                 -> RsmateResult<()> {
+  let mut data = Vec::new();
+  input.read_to_end(&mut data)?;
+  rmate::send_open_data(stream, opts, &data)?;
+  Ok(())
+}
 
+// The SHA-256 of the content of each file known to the editor, i.e. the one sent or saved
+// last, keyed by the name of the file.
+type Digests = Arc<Mutex<HashMap<String, Vec<u8>>>>;
 
-  #[derive(Debug)]
-  pub enum Cmd {
-    Save,
-    Close,
-  }
+fn file_digest(path: &Path) -> io::Result<Vec<u8>> {
+  let mut hasher = Sha256::new();
+  io::copy(&mut File::open(path)?, &mut hasher)?;
+  Ok(hasher.finalize().to_vec())
+}
 
-  #[derive(Debug)]
-  pub struct Command {
-    pub cmd: Cmd,
-    pub token: String,
-    pub data: String,
+// Record the content saved by the editor before writing it, so that the modification made by
+// the save itself is not sent back.
+struct WatchHandler {
+  inner: FilesystemHandler,
+  digests: Digests,
+}
+
+impl CommandHandler for WatchHandler {
+  fn on_save(&mut self, token: &str, data: &[u8]) -> rmate::RMateResult<()> {
+    self.digests.lock().unwrap().insert(token.to_owned(), Sha256::digest(data).to_vec());
+    self.inner.on_save(token, data)
   }
 
-  pub fn send_open<W: Write>(stream: &mut W, name: &str) -> RMateResult<()> {
-    let file_mmap = Mmap::open_path(name, Protection::Read)?;
+  fn on_close(&mut self, token: &str) -> rmate::RMateResult<()> {
+    self.inner.on_close(token)
+  }
+}
 
-    stream.write(b"open\n")?;
-    stream.write(format!("display-name: {}\n", name).as_bytes())?;
-    stream.write(format!("real-path: {:?}\n", canonicalize(name)?).as_bytes())?;
-    stream.write(b"data-on-save: yes\n")?;
-    stream.write(b"re-activate: yes\n")?;
-    stream.write(format!("token: {}\n", name).as_bytes())?;
-    stream.write(format!("data: {}\n", file_mmap.len()).as_bytes())?;
-    stream.write(unsafe { file_mmap.as_slice() })?;
-    stream.write(b"\n.\n")?;
-    stream.flush()?;
+enum WatchEvent {
+  // A file in the watched directories is modified.
+  Modified(PathBuf),
+  // The session of a file (the index in `names`) is closed, along with its serial number.
+  Closed(usize, u64, rmate::RMateResult<()>),
+}
 
-    Ok(())
-  }
+// A file being watched.
+struct WatchedFile<'a> {
+  name: &'a str,
+  // The canonical path of the file, as reported by the watcher.
+  path: PathBuf,
+  // The serial number and the socket of the current session, or `None` once it is closed.
+  session: Option<(u64, Socket)>,
+}
 
-  enum ReadCmd {
-    Command(Command),
-    Empty,
-    Eof,
-  }
+// Send the file on a new connection, and handle the commands from the server in a background
+// thread.
+fn spawn_session(options: &Options,
+                 index: usize,
+                 serial: u64,
+                 digests: &Digests,
+                 tx: &mpsc::Sender<WatchEvent>)
+                 -> RsmateResult<Socket> {
+  let name = &options.names[index];
+  let (mut stream, socket) = open_connection(options)?;
+  digests.lock().unwrap().insert(name.clone(), file_digest(Path::new(name))?);
+  rmate::send_open(&mut stream, &open_options(options, name))?;
+
+  let mut handler = WatchHandler {
+    inner: filesystem_handler(options),
+    digests: digests.clone(),
+  };
+  let max_data_size = options.max_data_size;
+  let tx = tx.clone();
+  thread::spawn(move || {
+    let result = session(stream, &mut handler, max_data_size);
+    let _ = tx.send(WatchEvent::Closed(index, serial, result));
+  });
+  Ok(socket)
+}
 
-  macro_rules! readline {
-    ($reader:expr) => {
-      {
-        let mut line = String::new();
-        let len = try!($reader.read_line(&mut line));
-        if len == 0 { return Ok(ReadCmd::Eof); }
-        line.trim_right().to_owned()
+// The events within this period (in ms) after the first one are handled at once.
+const WATCH_DEBOUNCE: u64 = 100;
+
+// Re-send each file every time it is modified locally.
+//
+// Each file is sent on its own connection, which is replaced when the file is re-sent.
+// The loop exits when all of the sessions are closed by the server (or the process is
+// interrupted).
+fn watch(options: &Options) -> RsmateResult<()> {
+  let (tx, rx) = mpsc::channel();
+  let digests: Digests = Arc::new(Mutex::new(HashMap::new()));
+
+  let watcher_tx = tx.clone();
+  let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+    if let Ok(event) = event {
+      if let EventKind::Modify(_) | EventKind::Create(_) = event.kind {
+        for path in event.paths {
+          let _ = watcher_tx.send(WatchEvent::Modified(path));
+        }
       }
     }
+  })?;
+
+  let mut files = Vec::with_capacity(options.names.len());
+  for name in &options.names {
+    let path = canonicalize(name)?;
+    // The directory is watched rather than the file, since saving the file (by rsmate itself
+    // or by most editors) replaces it with a new one, which the watch on the file would
+    // not follow.
+    if let Some(dir) = path.parent() {
+      watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    files.push(WatchedFile {
+      name,
+      path,
+      session: None,
+    });
   }
 
-  fn read_command<R: BufRead>(reader: &mut R) -> RMateResult<ReadCmd> {
-    let cmd = readline!(reader);
-    let cmd = match cmd.as_str() {
-      "save" => Cmd::Save,
-      "close" => Cmd::Close,
-      _ => return Ok(ReadCmd::Empty),
+  let mut serial = 0;
+  for (i, file) in files.iter_mut().enumerate() {
+    serial += 1;
+    file.session = Some((serial, spawn_session(options, i, serial, &digests, &tx)?));
+  }
+
+  while files.iter().any(|file| file.session.is_some()) {
+    let event = match rx.recv() {
+      Ok(event) => event,
+      Err(_) => break,
     };
+    // A single write may raise several events, so the ones in a short period are coalesced.
+    thread::sleep(Duration::from_millis(WATCH_DEBOUNCE));
+    let mut modified = Vec::new();
+    for event in Some(event).into_iter().chain(rx.try_iter()) {
+      match event {
+        WatchEvent::Modified(path) => {
+          if let Some(i) = files.iter().position(|file| file.path == path) {
+            if !modified.contains(&i) {
+              modified.push(i);
+            }
+          }
+        }
+        WatchEvent::Closed(i, closed, result) => {
+          // The replaced sessions are closed by ourselves.
+          let is_current = files[i].session.as_ref().map(|&(serial, _)| serial) == Some(closed);
+          if is_current {
+            if let Err(e) = result {
+              error!("{}: {}", files[i].name, e);
+            }
+            files[i].session = None;
+          }
+        }
+      }
+    }
 
-    let token = readline!(reader);
-    let token = token.split(':')
-      .nth(1)
-      .map(|s| s.trim())
-      .ok_or(Error::Parse("cannot parse token".to_owned()))?
-      .to_owned();
-
-    let len = readline!(reader);
-    let len = len.split(':')
-      .nth(1)
-      .map(|s| s.trim())
-      .ok_or(Error::Parse("cannot parse data length".to_owned()))?
-      .to_owned();
-    let len = len.parse::<usize>()?;
-
-    let mut buf = Vec::with_capacity(len);
-    buf.resize(len, 0u8);
-    reader.read_exact(buf.as_mut_slice())?;
-    let data = String::from_utf8(buf)?;
-
-    Ok(ReadCmd::Command(Command {
-      cmd: cmd,
-      token: token,
-      data: data,
-    }))
-  }
-
-  pub fn handle_commands<R: BufRead>(mut reader: R) -> RMateResult<()> {
-    loop {
-      let command = match read_command(&mut reader)? {
-        ReadCmd::Empty => continue,
-        ReadCmd::Eof => break,
-        ReadCmd::Command(command) => command,
+    for i in modified {
+      if files[i].session.is_none() {
+        continue;
+      }
+      let digest = match file_digest(&files[i].path) {
+        Ok(digest) => digest,
+        Err(e) => {
+          // The file may be in the middle of being replaced.
+          debug!("{}: {}", files[i].name, e);
+          continue;
+        }
       };
-      println!("{:?}", command);
+      if digests.lock().unwrap().get(files[i].name) == Some(&digest) {
+        continue;
+      }
 
-      match command.cmd {
-        Cmd::Save => {
-          use std::fs::OpenOptions;
-          let mut file = OpenOptions::new().write(true).create(true).open(command.token)?;
-          file.write_all(command.data.as_bytes())?;
-        }
-        Cmd::Close => {
-          // do nothing
+      debug!("re-sending {}", files[i].name);
+      if let Some((_, socket)) = files[i].session.take() {
+        let _ = socket.shutdown();
+      }
+      serial += 1;
+      files[i].session = Some((serial, spawn_session(options, i, serial, &digests, &tx)?));
+    }
+  }
+
+  Ok(())
+}
+
+// Connect to the server, retrying up to `retries` times with exponential backoff.
+fn connect_with_retry(host: &str,
+                      port: u32,
+                      retries: u32,
+                      delay: u64,
+                      timeout: Option<Duration>)
+                      -> io::Result<TcpStream> {
+  let addr = parse_addr(host, port)?;
+  let mut attempt = 0;
+  loop {
+    match connect(&addr, timeout) {
+      Ok(stream) => return Ok(stream),
+      Err(e) => {
+        if attempt >= retries {
+          return Err(e);
         }
+        let wait = delay.saturating_mul(1 << attempt.min(63));
+        warn!("failed to connect to {}: {} (retrying in {} ms)", addr, e, wait);
+        thread::sleep(Duration::from_millis(wait));
+        attempt += 1;
       }
     }
-    Ok(())
+  }
+}
+
+// Connect to the address, trying each of the resolved ones in turn as `TcpStream::connect`.
+fn connect(addr: &str, timeout: Option<Duration>) -> io::Result<TcpStream> {
+  let timeout = match timeout {
+    Some(timeout) => timeout,
+    None => return TcpStream::connect(addr),
+  };
+  let mut last_error = None;
+  for addr in addr.to_socket_addrs()? {
+    match TcpStream::connect_timeout(&addr, timeout) {
+      Ok(stream) => return Ok(stream),
+      Err(e) => last_error = Some(e),
+    }
+  }
+  Err(last_error.unwrap_or_else(|| {
+    io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address")
+  }))
+}
+
+// Format the address to connect, which is resolved with `ToSocketAddrs`.
+//
+// IPv6 addresses (e.g. `::1`) are enclosed in brackets, since the port would be ambiguous.
+fn parse_addr(host: &str, port: u32) -> io::Result<String> {
+  if host.is_empty() {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty host"));
+  }
+  if host.starts_with('[') || !host.contains(':') {
+    Ok(format!("{}:{}", host, port))
+  } else {
+    Ok(format!("[{}]:{}", host, port))
+  }
+}
+
+// Wrap the connection with TLS.
+fn connect_tls(stream: TcpStream,
+               host: &str,
+               options: &Options)
+               -> RsmateResult<rustls::StreamOwned<rustls::ClientConnection, TcpStream>> {
+  let mut roots = rustls::RootCertStore::empty();
+  match options.ca_cert {
+    Some(ref path) => {
+      let mut reader = BufReader::new(File::open(path)?);
+      for cert in rustls_pemfile::certs(&mut reader) {
+        roots.add(cert?)?;
+      }
+    }
+    None => {
+      for cert in rustls_native_certs::load_native_certs().certs {
+        roots.add(cert)?;
+      }
+    }
+  }
+
+  let config = rustls::ClientConfig::builder()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+  // The brackets of an IPv6 address (e.g. `[::1]`) are not a part of the name.
+  let host = host.trim_start_matches('[').trim_end_matches(']');
+  let server_name = rustls::pki_types::ServerName::try_from(host.to_owned())
+    .map_err(|_| rmate::Error::Parse(format!("invalid host name: {}", host)))?;
+  let conn = rustls::ClientConnection::new(Arc::new(config), server_name)?;
+
+  Ok(rustls::StreamOwned::new(conn, stream))
+}
+
+fn session<S, H>(stream: S, handler: &mut H, max_data_size: usize) -> rmate::RMateResult<()>
+  where S: Read,
+        H: CommandHandler
+{
+  // handle all commands
+  let mut reader = BufReader::new(stream);
+
+  let servername = {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    line.trim_end().to_owned()
+  };
+  debug!("{:?}", servername);
+
+  rmate::handle_commands(reader, handler, max_data_size)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn config_file_is_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, "host = \"example.com\"\nport = 12345\nwait = true\n").unwrap();
+
+    let config = Config::read(&path).unwrap();
+    assert_eq!(config.host, Some("example.com".to_owned()));
+    assert_eq!(config.port, Some(12345));
+    assert_eq!(config.wait, Some(true));
+  }
+
+  #[test]
+  fn missing_config_file_gives_the_defaults() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::read(&dir.path().join("config.toml")).unwrap();
+    assert_eq!((config.host, config.port, config.wait), (None, None, None));
+  }
+
+  #[test]
+  fn stdin_is_sent_with_its_length() {
+    // The same as `echo hello | rmate --stdin=greeting`.
+    let mut sent = Vec::new();
+    send_stdin(&mut sent, "hello\n".as_bytes(), &OpenOptions::new("greeting")).unwrap();
+    let sent = String::from_utf8(sent).unwrap();
+    assert!(sent.starts_with("open\ndisplay-name: greeting\n"), "{}", sent);
+    assert!(!sent.contains("real-path:"), "{}", sent);
+    assert!(sent.ends_with("token: greeting\ndata: 6\nhello\n\n.\n"), "{}", sent);
+  }
+
+  #[test]
+  fn malformed_config_file_is_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, "port = \"not a number\"\n").unwrap();
+
+    let message = Config::read(&path).unwrap_err();
+    assert!(message.starts_with(&format!("failed to parse {}", path.display())), "{}", message);
   }
 }
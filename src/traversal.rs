@@ -0,0 +1,398 @@
+//! The traversal of directory trees, shared among the utilities (e.g. `files`, `filedup`).
+//!
+//! The entries are scanned on background threads and passed through a channel, so that
+//! the output can start before the traversal is completed.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use rayon;
+
+use {Contexted, ExtContext};
+use config::DEFAULT_CHANNEL_BUFFER;
+use gitignore::Gitignore;
+use pattern::Pattern;
+
+/// A found entry, or the error which occurred while scanning a directory.
+pub type WalkResult = Result<fs::DirEntry, Contexted<io::Error>>;
+
+/// Options of the traversal.
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+  /// The entries whose names match the pattern are skipped.
+  pub ignore: Pattern,
+  /// Whether the directories are sent.
+  pub is_directory: bool,
+  /// Whether the files are sent.
+  pub include_files: bool,
+  /// Scan each subdirectory on a new thread.
+  pub is_async: bool,
+  /// Scan each subdirectory as a task on the Rayon thread pool.
+  pub parallel_rayon: bool,
+  pub max_depth: usize,
+  pub follow_symlinks: bool,
+  /// Skip the entries ignored by the `.gitignore` files.
+  pub gitignore: bool,
+  /// Drop the ignored directories without sending them.
+  pub prune: bool,
+  /// Turn the permission errors into warnings, instead of sending them.
+  pub skip_permission_errors: bool,
+  /// Descend into the directories on the other filesystems than the root (Unix only).
+  pub cross_fs: bool,
+  /// The traversal stops once this number of entries are sent.
+  pub limit: usize,
+  pub channel_buffer: usize,
+}
+
+impl Default for WalkOptions {
+  fn default() -> WalkOptions {
+    WalkOptions {
+      ignore: Pattern::empty(),
+      is_directory: false,
+      include_files: true,
+      is_async: false,
+      parallel_rayon: false,
+      max_depth: usize::MAX,
+      follow_symlinks: false,
+      gitignore: false,
+      prune: false,
+      skip_permission_errors: true,
+      cross_fs: true,
+      limit: usize::MAX,
+      channel_buffer: DEFAULT_CHANNEL_BUFFER,
+    }
+  }
+}
+
+/// Scan all files/directories under the root on background threads.
+pub fn walk<P: Into<PathBuf>>(root: P, opts: &WalkOptions) -> Receiver<WalkResult> {
+  let root = root.into();
+  let shared = Arc::new(Shared {
+    root_device: if opts.cross_fs {
+      None
+    } else {
+      device_id(&root).unwrap_or(None)
+    },
+    opts: opts.clone(),
+    sent: AtomicUsize::new(0),
+    visited: Mutex::new(HashSet::new()),
+  });
+  let rules = Arc::new(Gitignore::new());
+  let max_depth = opts.max_depth;
+
+  let (tx, rx) = channel::bounded(opts.channel_buffer);
+  if opts.parallel_rayon {
+    thread::spawn(move || {
+      rayon::scope(|scope| spawn_inner(&root, tx, shared, rules, max_depth, Some(scope)))
+    });
+  } else {
+    thread::spawn(move || spawn_inner(&root, tx, shared, rules, max_depth, None));
+  }
+
+  rx
+}
+
+// The options and the state of the traversal, shared among the scanning threads.
+#[derive(Debug)]
+struct Shared {
+  opts: WalkOptions,
+  // The device of the root, if the traversal stays on its filesystem.
+  root_device: Option<u64>,
+  // The number of entries sent so far.
+  sent: AtomicUsize,
+  // The directories already scanned.
+  visited: Mutex<HashSet<FileId>>,
+}
+
+impl Shared {
+  fn is_done(&self) -> bool {
+    self.sent.load(Ordering::SeqCst) >= self.opts.limit
+  }
+
+  // Send the entry, and return whether the traversal should continue.
+  fn send(&self, tx: &Sender<WalkResult>, entry: fs::DirEntry) -> bool {
+    if self.sent.fetch_add(1, Ordering::SeqCst) >= self.opts.limit {
+      return false;
+    }
+    // The receiver has gone away when the output is completed.
+    tx.send(Ok(entry)).is_ok()
+  }
+}
+
+// Scan the directory, and send the error (if any) to the receiver.
+fn spawn_inner<'s>(entry: &Path,
+                   tx: Sender<WalkResult>,
+                   shared: Arc<Shared>,
+                   rules: Arc<Gitignore>,
+                   depth: usize,
+                   scope: Option<&rayon::Scope<'s>>) {
+  if let Err(e) = walk_inner(entry, tx.clone(), shared, rules, depth, scope) {
+    let _ = tx.send(Err(e));
+  }
+}
+
+// Scan the directory `entry`.
+//
+// If `scope` is given, the subdirectories are scanned as the tasks on the Rayon thread pool.
+fn walk_inner<'s>(entry: &Path,
+                  tx: Sender<WalkResult>,
+                  shared: Arc<Shared>,
+                  rules: Arc<Gitignore>,
+                  depth: usize,
+                  scope: Option<&rayon::Scope<'s>>)
+                  -> Result<(), Contexted<io::Error>> {
+  let opts = &shared.opts;
+
+  // The entries at deeper levels are not scanned.
+  if depth == 0 || shared.is_done() {
+    return Ok(());
+  }
+
+  let skip = opts.skip_permission_errors;
+
+  // Symbolic links (or hard-linked directories) may form a loop,
+  // so each directory is scanned at most once.
+  let context = || entry.display().to_string();
+  match skip_permission_error(visit(&shared.visited, entry), entry, skip).with_context(context)? {
+    Some(true) => (),
+    Some(false) | None => return Ok(()),
+  }
+
+  // The rules of the parent directories also apply to the entries in this directory.
+  let rules = if opts.gitignore {
+    match skip_permission_error(rules.with_dir(entry), entry, skip).with_context(context)? {
+      Some(rules) => Arc::new(rules),
+      None => return Ok(()),
+    }
+  } else {
+    rules
+  };
+
+  let entries = match skip_permission_error(fs::read_dir(entry), entry, skip)
+    .with_context(context)? {
+    Some(entries) => entries,
+    None => return Ok(()),
+  };
+
+  for entry in entries {
+    let entry = entry.with_context(context)?;
+    let file_type = entry.file_type().with_context(|| entry.path().display().to_string())?;
+    let is_entry_dir = if opts.follow_symlinks && file_type.is_symlink() {
+      // `Path::is_dir()` follows the link.
+      entry.path().is_dir()
+    } else {
+      file_type.is_dir()
+    };
+
+    if opts.gitignore && rules.is_ignored(&entry.path(), is_entry_dir) {
+      continue;
+    }
+
+    if !is_entry_dir {
+      if opts.include_files && !opts.ignore.matches(&entry.path()) && !shared.send(&tx, entry) {
+        return Ok(());
+      }
+
+    } else {
+      // Ignored directories are dropped here, so that they are neither shown nor scanned.
+      if opts.prune && opts.ignore.matches(&entry.path()) {
+        continue;
+      }
+
+      let path = entry.path().to_owned();
+      let tx = tx.clone();
+      let shared = shared.clone();
+      let rules = rules.clone();
+
+      if shared.opts.is_directory && !shared.send(&tx, entry) {
+        return Ok(());
+      }
+
+      // The ignored directories are not scanned either. The root is never checked here,
+      // since it is given explicitly (e.g. `files ~/.config`).
+      if opts.ignore.matches(&path) {
+        continue;
+      }
+
+      // The mount points are shown, but not scanned.
+      if let Some(root_device) = shared.root_device {
+        if device_id(&path).with_context(|| path.display().to_string())? != Some(root_device) {
+          continue;
+        }
+      }
+
+      if let Some(scope) = scope {
+        scope.spawn(move |scope| spawn_inner(&path, tx, shared, rules, depth - 1, Some(scope)));
+      } else if shared.opts.is_async {
+        thread::spawn(move || spawn_inner(&path, tx, shared, rules, depth - 1, None));
+      } else {
+        walk_inner(&path, tx, shared, rules, depth - 1, None)?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+// The identity of a directory: (device, inode) on Unix.
+#[cfg(unix)]
+type FileId = (u64, u64);
+
+// Inode numbers are not available on Windows, so the canonical path is used instead.
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+// Return the ID of the device on which the file resides, or `None` on non-Unix platforms.
+#[cfg(unix)]
+fn device_id(path: &Path) -> io::Result<Option<u64>> {
+  use std::os::unix::fs::MetadataExt;
+  Ok(Some(fs::metadata(path)?.dev()))
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> io::Result<Option<u64>> {
+  Ok(None)
+}
+
+// Mark the directory as visited, and return whether it was not visited yet.
+#[cfg(unix)]
+fn visit(visited: &Mutex<HashSet<FileId>>, path: &Path) -> io::Result<bool> {
+  use std::os::unix::fs::MetadataExt;
+  let metadata = fs::metadata(path)?;
+  Ok(visited.lock().unwrap().insert((metadata.dev(), metadata.ino())))
+}
+
+#[cfg(not(unix))]
+fn visit(visited: &Mutex<HashSet<FileId>>, path: &Path) -> io::Result<bool> {
+  let path = fs::canonicalize(path)?;
+  Ok(visited.lock().unwrap().insert(path))
+}
+
+/// Turn a permission error into a warning if `skip` is set, and return `None` in that case.
+pub fn skip_permission_error<T>(result: io::Result<T>,
+                                path: &Path,
+                                skip: bool)
+                                -> io::Result<Option<T>> {
+  match result {
+    Ok(value) => Ok(Some(value)),
+    Err(ref e) if skip && e.kind() == io::ErrorKind::PermissionDenied => {
+      eprintln!("warning: {}: {}", path.display(), e);
+      Ok(None)
+    }
+    Err(e) => Err(e),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile;
+
+  // Collect the paths of the found entries, relative to the root.
+  fn walk_paths(root: &Path, opts: &WalkOptions) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = walk(root, opts)
+      .into_iter()
+      .map(|entry| entry.unwrap().path().strip_prefix(root).unwrap().to_owned())
+      .collect();
+    paths.sort();
+    paths
+  }
+
+  #[test]
+  fn ignore_is_not_applied_to_the_root() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join(".config");
+    fs::create_dir_all(root.join(".hidden")).unwrap();
+    fs::write(root.join("a"), "").unwrap();
+    fs::write(root.join(".b"), "").unwrap();
+    fs::write(root.join(".hidden/c"), "").unwrap();
+
+    let opts = WalkOptions { ignore: Pattern::new(r"^\.").unwrap(), ..WalkOptions::default() };
+    assert_eq!(walk_paths(&root, &opts), vec![PathBuf::from("a")]);
+  }
+
+  // The tree used by the tests below:
+  //   a, b/c, b/d/e, .f/g
+  fn fixture() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("b/d")).unwrap();
+    fs::create_dir_all(dir.path().join(".f")).unwrap();
+    for name in &["a", "b/c", "b/d/e", ".f/g"] {
+      fs::write(dir.path().join(name), "").unwrap();
+    }
+    dir
+  }
+
+  // The options of each scanning mode: synchronous, one thread per directory, and Rayon.
+  fn modes(opts: WalkOptions) -> Vec<WalkOptions> {
+    vec![opts.clone(),
+         WalkOptions { is_async: true, ..opts.clone() },
+         WalkOptions { parallel_rayon: true, ..opts }]
+  }
+
+  fn paths(paths: &[&str]) -> Vec<PathBuf> {
+    paths.iter().map(PathBuf::from).collect()
+  }
+
+  #[test]
+  fn all_files_are_found_in_each_mode() {
+    let dir = fixture();
+    for opts in modes(WalkOptions::default()) {
+      assert_eq!(walk_paths(dir.path(), &opts),
+                 paths(&[".f/g", "a", "b/c", "b/d/e"]),
+                 "{:?}",
+                 opts);
+    }
+  }
+
+  #[test]
+  fn directories_are_sent_but_not_the_root() {
+    let dir = fixture();
+    for opts in modes(WalkOptions { is_directory: true, ..WalkOptions::default() }) {
+      assert_eq!(walk_paths(dir.path(), &opts),
+                 paths(&[".f", ".f/g", "a", "b", "b/c", "b/d", "b/d/e"]),
+                 "{:?}",
+                 opts);
+    }
+  }
+
+  #[test]
+  fn max_depth_limits_the_scanned_levels() {
+    let dir = fixture();
+    for opts in modes(WalkOptions { max_depth: 2, ..WalkOptions::default() }) {
+      assert_eq!(walk_paths(dir.path(), &opts),
+                 paths(&[".f/g", "a", "b/c"]),
+                 "{:?}",
+                 opts);
+    }
+    for opts in modes(WalkOptions { max_depth: 0, ..WalkOptions::default() }) {
+      assert!(walk_paths(dir.path(), &opts).is_empty(), "{:?}", opts);
+    }
+  }
+
+  #[test]
+  fn ignored_directories_are_not_scanned() {
+    let dir = fixture();
+    let ignore = Pattern::new(r"^\.|^d$").unwrap();
+    for opts in modes(WalkOptions { ignore: ignore.clone(), ..WalkOptions::default() }) {
+      assert_eq!(walk_paths(dir.path(), &opts), paths(&["a", "b/c"]), "{:?}", opts);
+    }
+
+    // Without `prune`, the ignored directories themselves are still sent.
+    let opts = WalkOptions { ignore, is_directory: true, ..WalkOptions::default() };
+    for opts in modes(opts) {
+      assert_eq!(walk_paths(dir.path(), &opts),
+                 paths(&[".f", "a", "b", "b/c", "b/d"]),
+                 "{:?}",
+                 opts);
+      let opts = WalkOptions { prune: true, ..opts };
+      assert_eq!(walk_paths(dir.path(), &opts), paths(&["a", "b", "b/c"]), "{:?}", opts);
+    }
+  }
+}
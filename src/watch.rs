@@ -0,0 +1,119 @@
+extern crate docopt;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate notify;
+extern crate regex;
+#[macro_use]
+extern crate rust_miscs;
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{exit, Child};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use rust_miscs::command;
+use rust_miscs::pattern::Pattern;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+  flag_pattern: String,
+  flag_dir: String,
+  flag_clear: bool,
+  flag_once: bool,
+  arg_command: String,
+  arg_args: Vec<String>,
+}
+
+const USAGE: &str = r"
+Re-run a command when the files change
+
+Usage:
+  watch [options] <command> [<args>...]
+  watch (-h | --help)
+
+Options:
+  -h --help         Show this message.
+  --pattern=<REGEX>
+                    Only the changes of the files whose names match REGEX trigger
+                    the command [default: .*]
+  --dir=<DIR>       The directory watched recursively [default: .]
+  --clear           Clear the terminal before each run
+  --once            Run the command on the first change, and exit with its status
+";
+
+// The changes within this interval after the last one are coalesced into a single run.
+const DEBOUNCE_INTERVAL: u64 = 200;
+
+def_error! {
+  WatchError {
+    Io(io::Error)         => "I/O error: {0}",
+    Notify(notify::Error) => "watch error: {0}",
+    Regex(regex::Error)   => "invalid pattern: {0}",
+  }
+}
+
+fn main() {
+  let args: Args = docopt::Docopt::new(USAGE)
+    .and_then(|opt| opt.deserialize())
+    .unwrap_or_else(|e| e.exit());
+
+  match run(args) {
+    Ok(code) => exit(code),
+    Err(e) => {
+      eprintln!("error: {}", e);
+      exit(1);
+    }
+  }
+}
+
+fn run(args: Args) -> Result<i32, WatchError> {
+  let pattern = Pattern::new(&args.flag_pattern)?;
+
+  let (tx, rx) = mpsc::channel();
+  let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+    if let Ok(event) = event {
+      match event.kind {
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
+          for path in event.paths {
+            let _ = tx.send(path);
+          }
+        }
+        _ => (),
+      }
+    }
+  })?;
+  watcher.watch(&PathBuf::from(&args.flag_dir), RecursiveMode::Recursive)?;
+
+  let mut running: Option<Child> = None;
+  while let Ok(path) = rx.recv() {
+    if !pattern.matches(&path) {
+      continue;
+    }
+    // Wait until the changes settle down, e.g. while an editor saves several files.
+    let interval = Duration::from_millis(DEBOUNCE_INTERVAL);
+    while rx.recv_timeout(interval).is_ok() {}
+
+    if let Some(mut child) = running.take() {
+      if child.try_wait()?.is_none() {
+        // It may exit just before being killed, which is not an error.
+        let _ = child.kill();
+        child.wait()?;
+      }
+    }
+
+    if args.flag_clear {
+      print!("\x1b[2J\x1b[H");
+      io::stdout().flush()?;
+    }
+    let mut child = command::run_command(&args.arg_command, &args.arg_args, false)?;
+    if args.flag_once {
+      return Ok(child.wait()?.code().unwrap_or(1));
+    }
+    running = Some(child);
+  }
+
+  Ok(0)
+}